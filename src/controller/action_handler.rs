@@ -1,7 +1,12 @@
 use rdev::{simulate, Button, EventType, Key, SimulateError};
-use std::{thread, time, collections::HashMap};
+use std::{thread, time::{self, Duration, Instant}, panic, sync::Mutex, collections::{HashMap, HashSet}};
 
 use crate::settings::{alert_and_exit_on_invalid_settings};
+use crate::screen_coordinates;
+
+// Release events for everything pressed at the OS level and not yet let go.
+// Lives outside ActionHandler so a panic hook can still release it.
+static OS_HELD_INPUT: Mutex<Vec<EventType>> = Mutex::new(Vec::new());
 
 #[derive(PartialEq)]
 pub enum ActionType {
@@ -14,29 +19,42 @@ pub struct ActionHandler {
     middle_mouse_held: bool,
     right_mouse_held: bool,
     held_keys: HashMap<Key, String>,
-    holding_left_click_for_action: bool,
+    // When each held key and mouse button went down, for the stuck input watchdog
+    key_held_since: HashMap<Key, Instant>,
+    mouse_held_since: HashMap<Button, Instant>,
+    // Set to the modifier click (e.g. "altleftclick") while it holds left click, rather than walking
+    left_click_action: Option<String>,
+    // Lowercase actions that count as abilities for cursor swivel and action distances
+    ability_actions: HashSet<String>,
 }
 
-impl Default for ActionHandler {
-    fn default() -> Self {
+impl ActionHandler {
+    pub fn new(ability_actions: HashSet<String>) -> Self {
         ActionHandler {
             left_mouse_held: false,
             middle_mouse_held: false,
             right_mouse_held: false,
             held_keys: HashMap::<Key, String>::with_capacity(20),
-            holding_left_click_for_action: false,
+            key_held_since: HashMap::new(),
+            mouse_held_since: HashMap::new(),
+            left_click_action: None,
+            ability_actions: ability_actions,
         }
     }
-}
 
-impl ActionHandler {
     pub fn handle_action(&mut self, action_type: ActionType, action: String) {
         let action_lower = action.to_lowercase();
         let action_str = action_lower.as_str();
         match action_str {
             // Check for known "special" cases first.
             "altleftclick" => { 
-                self.handle_action_with_modifier_key(action_type, "leftclick".to_owned(), "alt".to_owned(), 20, 10);
+                self.handle_action_with_modifier_key(action_type, action_lower.clone(), "leftclick".to_owned(), "alt".to_owned(), 20, 10);
+            },
+            "ctrlleftclick" => { 
+                self.handle_action_with_modifier_key(action_type, action_lower.clone(), "leftclick".to_owned(), "control".to_owned(), 20, 10);
+            },
+            "shiftleftclick" => { 
+                self.handle_action_with_modifier_key(action_type, action_lower.clone(), "leftclick".to_owned(), "shift".to_owned(), 20, 10);
             },
             // Not a special case, fall through
             _ => { 
@@ -50,6 +68,7 @@ impl ActionHandler {
                     self.handle_keypress_action(key_button, action_type, action);
                 } else {
                     // TODO: We should probably check this on config load, but keeping it here for now because if bindable keys / actions changes, it'll happen here.
+                    self.release_all();
                     alert_and_exit_on_invalid_settings(&format!("Invalid action configured in settings: {:?}", action_str));
                     panic!("Invalid action configured in settings: {:?}", action_str); 
                 }
@@ -58,8 +77,50 @@ impl ActionHandler {
         }
     }
 
+    // Lets go of every key and mouse button we're holding down at the OS level
+    pub fn release_all(&mut self) {
+        for mouse_button in [Button::Left, Button::Middle, Button::Right] {
+            self.handle_mouse_action(mouse_button, ActionType::Release);
+        }
+        self.left_click_action = None;
+        let held_keys: Vec<Key> = self.held_keys.keys().cloned().collect();
+        for key in held_keys {
+            rdev_send_event(&EventType::KeyRelease(key));
+        }
+        self.held_keys.clear();
+        self.key_held_since.clear();
+        // Anything ActionHandler lost track of
+        release_os_held_input();
+    }
+
+    // Lowercase actions that have been held down for longer than max_hold
+    pub fn actions_held_longer_than(&self, max_hold: Duration) -> Vec<String> {
+        let mut held_actions: Vec<String> = self.mouse_held_since.iter()
+                                                    .filter(|(_mouse_button, held_since)| held_since.elapsed() > max_hold)
+                                                    .map(|(mouse_button, _held_since)| self.match_button_to_mouse_str(*mouse_button).to_owned())
+                                                    .collect();
+        held_actions.extend(self.key_held_since.iter()
+                                .filter(|(_key, held_since)| held_since.elapsed() > max_hold)
+                                .map(|(key, _held_since)| self.held_keys[key].to_lowercase()));
+        held_actions
+    }
+
+    // Releases whatever is held down under this action, skipping the special cases in handle_action
+    pub fn release_held_action(&mut self, held_action: &str) {
+        if let Some(mouse_button) = self.match_mouse_str_to_button(held_action) {
+            self.handle_mouse_action(mouse_button, ActionType::Release);
+        }
+        let held_keys: Vec<Key> = self.held_keys.iter()
+                                        .filter(|(_key, action)| action.to_lowercase() == held_action)
+                                        .map(|(key, _action)| *key)
+                                        .collect();
+        for key in held_keys {
+            self.handle_keypress_action(key, ActionType::Release, held_action.to_owned());
+        }
+    }
+
     pub fn holding_left_click_for_action(&self) -> bool {
-        self.holding_left_click_for_action
+        self.left_click_action.is_some()
     }
 
     fn match_mouse_str_to_button(&self, mouse_str: &str) -> Option<Button> {
@@ -71,6 +132,15 @@ impl ActionHandler {
         }
     }
 
+    fn match_button_to_mouse_str(&self, mouse_button: Button) -> &str {
+        match mouse_button {
+            Button::Left => "leftclick",
+            Button::Middle => "middleclick",
+            Button::Right => "rightclick",
+            _ => "",
+        }
+    }
+
     fn match_key_str_to_key(&self, key_str: &str) -> Option<Key> {
         match key_str {            
             "f1" => {Some(Key::F1)},
@@ -144,7 +214,9 @@ impl ActionHandler {
         }
     }
 
+    // Takes global desktop coordinates
     pub fn move_mouse(&self, x: f64, y: f64) {
+        let (x, y) = screen_coordinates::to_input_pos(x, y);
         rdev_send_event(&EventType::MouseMove { x, y });
     }
 
@@ -157,6 +229,8 @@ impl ActionHandler {
                     rdev_send_event(&EventType::ButtonPress(mouse_button))
                 } else if action == ActionType::Release && self.left_mouse_held {
                     self.left_mouse_held = false;
+                    // Nothing is holding left click once it's up, even if a modifier click pressed it
+                    self.left_click_action = None;
                     rdev_send_event(&EventType::ButtonRelease(mouse_button))
                 }
             },
@@ -180,6 +254,17 @@ impl ActionHandler {
             },
             _ => ()
         }
+        let mouse_held = match mouse_button {
+            Button::Left => self.left_mouse_held,
+            Button::Middle => self.middle_mouse_held,
+            Button::Right => self.right_mouse_held,
+            _ => return,
+        };
+        if mouse_held {
+            self.mouse_held_since.entry(mouse_button).or_insert_with(Instant::now);
+        } else {
+            self.mouse_held_since.remove(&mouse_button);
+        }
     }
 
     fn handle_keypress_action(&mut self, keypress: Key, action: ActionType, action_string: String) {
@@ -187,16 +272,18 @@ impl ActionHandler {
             if !self.held_keys.contains_key(&keypress) {
                 rdev_send_event(&EventType::KeyPress(keypress));
                 self.held_keys.insert(keypress, action_string);
+                self.key_held_since.insert(keypress, Instant::now());
             }
         } else if action == ActionType::Release {
             if self.held_keys.contains_key(&keypress) {
                 rdev_send_event(&EventType::KeyRelease(keypress));
                 self.held_keys.remove(&keypress);
+                self.key_held_since.remove(&keypress);
             }
         }
     }
 
-    fn handle_action_with_modifier_key(&mut self, action_type: ActionType, action: String, modifier: String, delay_ms_before: u64, delay_ms_after: u64) {
+    fn handle_action_with_modifier_key(&mut self, action_type: ActionType, action_name: String, action: String, modifier: String, delay_ms_before: u64, delay_ms_after: u64) {
         // We can trust this lookup so long as we only call this function with known inputs. 
         // If inputs are user-specified, we must refactor to check them.
         let modifier_key = self.match_key_str_to_key(&modifier).unwrap();
@@ -213,7 +300,7 @@ impl ActionHandler {
             if let Some(mouse_button) = self.match_mouse_str_to_button(&action) {
                 // println!("pushing mouse action {:?}", &action);
                 self.handle_mouse_action(mouse_button, ActionType::Press);
-                self.holding_left_click_for_action = true;
+                self.left_click_action = Some(action_name);
             } else if let Some(key_button) = self.match_key_str_to_key(&action) {
                 // This will not press again if another controller button is already holding this key
                 self.handle_keypress_action(key_button, ActionType::Press, action.clone());
@@ -227,7 +314,7 @@ impl ActionHandler {
         else if action_type == ActionType::Release {
             if let Some(mouse_button) = self.match_mouse_str_to_button(&action) {
                 self.handle_mouse_action(mouse_button, ActionType::Release);
-                self.holding_left_click_for_action = false;
+                self.left_click_action = None;
             } else if let Some(key_button) = self.match_key_str_to_key(&action) {
                 // This will unpress even if another controller button is already holding this key
                 self.handle_keypress_action(key_button, ActionType::Release, action.clone());
@@ -236,38 +323,78 @@ impl ActionHandler {
     }
 
     pub fn is_ability_key_held(&self) -> bool {
-        let mut is_holding = false;
-        if self.middle_mouse_held || self.right_mouse_held {
-            is_holding = true;
-        } 
-        for key in self.held_keys.keys() {
-            match key {
-                Key::KeyQ => {is_holding = true;},
-                Key::KeyW => {is_holding = true;},
-                Key::KeyE => {is_holding = true;},
-                Key::KeyR => {is_holding = true;},
-                Key::KeyT => {is_holding = true;},
-                _ => (),
-            }
-        }
-        is_holding
+        !self.get_held_ability_actions().is_empty()
+    }
+
+    // Everything we're holding down at the OS level, mouse buttons first
+    pub fn get_held_actions(&self) -> Vec<String> {
+        let mut held_actions = Vec::<String>::new();
+        if self.left_mouse_held {held_actions.push(self.left_click_action.clone().unwrap_or("leftclick".to_owned()));}
+        if self.middle_mouse_held {held_actions.push("middleclick".to_owned());}
+        if self.right_mouse_held {held_actions.push("rightclick".to_owned());}
+        let mut held_keys: Vec<String> = self.held_keys.values().map(|action| action.to_lowercase()).collect();
+        held_keys.sort();
+        held_actions.extend(held_keys);
+        held_actions
     }
+
     pub fn get_held_ability_actions(&self) -> Vec<String> {
-        let mut held_ability_actions = Vec::<String>::new();
-        if self.middle_mouse_held {held_ability_actions.push("MiddleClick".to_owned());}
-        if self.right_mouse_held {held_ability_actions.push("RightClick".to_owned());}
-        if self.held_keys.contains_key(&Key::KeyQ) {held_ability_actions.push("q".to_owned());}
-        if self.held_keys.contains_key(&Key::KeyW) {held_ability_actions.push("w".to_owned());}
-        if self.held_keys.contains_key(&Key::KeyE) {held_ability_actions.push("e".to_owned());}
-        if self.held_keys.contains_key(&Key::KeyR) {held_ability_actions.push("r".to_owned());}
-        if self.held_keys.contains_key(&Key::KeyT) {held_ability_actions.push("t".to_owned());}
-        held_ability_actions
+        let mut held_actions = Vec::<String>::new();
+        if self.middle_mouse_held {held_actions.push("middleclick".to_owned());}
+        if self.right_mouse_held {held_actions.push("rightclick".to_owned());}
+        if let Some(left_click_action) = &self.left_click_action {held_actions.push(left_click_action.clone());}
+        for action in self.held_keys.values() {
+            held_actions.push(action.to_lowercase());
+        }
+        held_actions.retain(|action| self.ability_actions.contains(action));
+        held_actions
+    }
+}
+
+impl Drop for ActionHandler {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+// A panic skips the overlay's cleanup, so keys would stay pressed after we're gone
+pub fn release_held_input_on_panic() {
+    let default_panic_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        release_os_held_input();
+        default_panic_hook(panic_info);
+    }));
+}
+
+fn release_os_held_input() {
+    // try_lock, a panic while holding the lock would otherwise deadlock the panic hook
+    let releases: Vec<EventType> = match OS_HELD_INPUT.try_lock() {
+        Ok(mut held_input) => held_input.drain(..).collect(),
+        Err(_) => return,
+    };
+    for release in releases {
+        if simulate(&release).is_err() {
+            println!("We could not send {:?}", release);
+        }
+    }
+}
+
+fn track_os_held_input(event_type: &EventType) {
+    let mut held_input = match OS_HELD_INPUT.lock() {
+        Ok(held_input) => held_input,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match event_type {
+        EventType::KeyPress(key) => held_input.push(EventType::KeyRelease(*key)),
+        EventType::ButtonPress(button) => held_input.push(EventType::ButtonRelease(*button)),
+        EventType::KeyRelease(_) | EventType::ButtonRelease(_) => held_input.retain(|release| release != event_type),
+        _ => (),
     }
 }
 
 fn rdev_send_event(event_type: &EventType) {
     match simulate(event_type) {
-        Ok(()) => (),
+        Ok(()) => track_os_held_input(event_type),
         Err(SimulateError) => {
             println!("We could not send {:?}", event_type);
         }
@@ -1,19 +1,46 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use std::{cell::RefCell, rc::Rc};
 
 use crate::game_window_tracker::GameWindowTracker;
-use crate::settings:: ApplicationSettings;
+use crate::settings::{ApplicationSettings, ActionDistanceSetting, ControllerSettings, RangePolicy, ScreenDistance};
 
 use super::input::{ControllerButton, AnalogStick};
 use super::action_handler::{ActionHandler, ActionType};
+use super::grid_navigator::{GridNavigator, GridDirection};
+use super::ui_state::{UiStateTracker, UiState};
+use super::aim_ring::AimRing;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ControlMode {
+    TwinStick,
+    Cursor,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum ActionDistance {
     Close,
     Mid,
     Far,
+    Radius(ScreenDistance),
+    StickMagnitude { min: ScreenDistance, max: ScreenDistance },
+    ClampCursor { min: ScreenDistance, max: ScreenDistance },
+    Cursor,
     None,
 }
 
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeldActionPriority {
+    Farthest,
+    MostRecent,
+    Priority,
+}
+
+impl Default for HeldActionPriority {
+    fn default() -> Self {HeldActionPriority::Farthest}
+}
+
 struct PlannedAction {
     name: String,
     just_pressed: bool,
@@ -24,38 +51,72 @@ struct PlannedAction {
 pub struct ActionManager {
     action_handler: ActionHandler,
     planned_actions: Vec<PlannedAction>,
-    game_window_tracker: GameWindowTracker,
+    game_window_tracker: Rc<RefCell<GameWindowTracker>>,
+    // Settings as loaded, before the active game's profile is applied
+    base_settings: ApplicationSettings,
     settings: ApplicationSettings,
+    active_game: Option<String>,
+    control_mode: ControlMode,
+    grid_navigator: GridNavigator,
+    ui_state_tracker: UiStateTracker,
+    // Remembers which key each held button pressed, so it is released even if the control mode changes while held
+    pressed_bindings: HashMap<String, String>,
+    // Held buttons, oldest press first
+    press_order: Vec<String>,
+    // Buttons held on the controller right now, whether or not we saw them get pressed
+    held_buttons: HashSet<String>,
     holding_walk: bool,
     walking_angle: f32,
+    walking_stick_direction: Vec<f32>,
+    walking_stick_magnitude: f32,
     holding_aim: bool,
     aiming_angle: f32,
     aiming_stick_direction: Vec<f32>,
+    aiming_stick_magnitude: f32,
     aiming_stick_pull_amount: f32,
     holding_ability: bool,
+    // Where safe_move_mouse last put the cursor, in desktop pixels, for the debug overlay
+    last_cursor_target: Option<(f64, f64)>,
     
 }
 
 impl ActionManager {
-    pub fn initialize (application_settings: ApplicationSettings, game_window_tracker: GameWindowTracker) -> ActionManager {
+    pub fn initialize (application_settings: ApplicationSettings, game_window_tracker: Rc<RefCell<GameWindowTracker>>) -> ActionManager {
         ActionManager {
-            action_handler: ActionHandler::default(),
+            action_handler: ActionHandler::new(application_settings.ability_mapping_settings().keys().cloned().collect()),
             planned_actions: Vec::<PlannedAction>::with_capacity(application_settings.button_mapping_settings().keys().count()), 
             game_window_tracker: game_window_tracker,
+            grid_navigator: GridNavigator::new(application_settings.grid_navigation_settings()),
+            ui_state_tracker: UiStateTracker::new(application_settings.ui_state_settings()),
+            base_settings: application_settings.clone(),
             settings: application_settings,
+            active_game: None,
+            control_mode: ControlMode::TwinStick,
+            pressed_bindings: HashMap::new(),
+            press_order: Vec::new(),
+            held_buttons: HashSet::new(),
             holding_walk: false,
             walking_angle: 0.0,
+            walking_stick_direction: vec![0.0, 0.0],
+            walking_stick_magnitude: 0.0,
             holding_aim: false,
             aiming_angle: 0.0,
             aiming_stick_direction: vec![0.0, 0.0],
+            aiming_stick_magnitude: 0.0,
             aiming_stick_pull_amount: 0.0,
             holding_ability: false,
+            last_cursor_target: None,
 
         }
     }
 
     pub fn process_input_buttons(&mut self, named_controller_buttons: HashMap<String, &mut ControllerButton>) {
         for (action_name, button) in named_controller_buttons {
+            if button.held {
+                self.held_buttons.insert(action_name.clone());
+            } else {
+                self.held_buttons.remove(&action_name);
+            }
             if button.just_pressed {
                 println!("Just pressed {:?} ", action_name);
                 let can_be_aimed = self.settings.aimable_buttons().contains(&action_name);
@@ -83,6 +144,8 @@ impl ActionManager {
         if !left_stick.joystick_in_deadzone() {
             self.holding_walk = true;
             self.walking_angle = left_stick.stick_angle();
+            self.walking_stick_direction = left_stick.stick_direction();
+            self.walking_stick_magnitude = left_stick.stick_magnitude();
         } else {
             self.holding_walk = false;
         }
@@ -90,6 +153,7 @@ impl ActionManager {
             self.holding_aim = true;
             self.aiming_angle = right_stick.stick_angle();
             self.aiming_stick_direction = right_stick.stick_direction();
+            self.aiming_stick_magnitude = right_stick.stick_magnitude();
             self.aiming_stick_pull_amount = right_stick.joystick_pull_amount_smoothed();
         } else {
             self.holding_aim = false;
@@ -97,41 +161,107 @@ impl ActionManager {
         }
     }
 
-    pub fn update_window_tracker (&mut self) {self.game_window_tracker.update_window_tracker()}
+    pub fn control_mode(&self) -> ControlMode {self.control_mode}
+    pub fn holding_walk(&self) -> bool {self.holding_walk}
+    pub fn walking_angle(&self) -> f32 {self.walking_angle}
+    pub fn holding_aim(&self) -> bool {self.holding_aim}
+    pub fn aiming_angle(&self) -> f32 {self.aiming_angle}
+    pub fn last_cursor_target(&self) -> Option<(f64, f64)> {self.last_cursor_target}
+    pub fn held_actions(&self) -> Vec<String> {self.action_handler.get_held_actions()}
+    pub fn set_controller_settings(&mut self, controller_settings: ControllerSettings) {
+        self.base_settings.set_controller_settings(controller_settings.clone());
+        self.settings.set_controller_settings(controller_settings);
+    }
+
+    // Loads the profile of whichever configured game has focus. With none focused, the last game's profile stays.
+    pub fn follow_active_game(&mut self) {
+        let active_game = match self.game_window_tracker.borrow().active_game() {
+            Some(active_game) => active_game,
+            None => return,
+        };
+        if self.active_game.as_ref() == Some(&active_game) {
+            return
+        }
+        // Anything held was pressed with the previous game's bindings
+        self.release_all();
+        self.settings = match self.base_settings.profile_for_game(&active_game) {
+            Some(profile_name) => {
+                println!("{} is active, loading profile {}", active_game, profile_name);
+                self.base_settings.with_profile(&profile_name)
+            },
+            None => {
+                println!("{} is active", active_game);
+                self.base_settings.clone()
+            },
+        };
+        self.action_handler = ActionHandler::new(self.settings.ability_mapping_settings().keys().cloned().collect());
+        self.active_game = Some(active_game);
+    }
+
+    // Forgets every held button and lets go of everything sent to the game.
+    // Buttons still held on the controller need to be pressed again to act.
+    pub fn release_all(&mut self) {
+        self.planned_actions.clear();
+        self.pressed_bindings.clear();
+        self.press_order.clear();
+        self.held_buttons.clear();
+        self.holding_walk = false;
+        self.holding_aim = false;
+        self.holding_ability = false;
+        self.aiming_stick_pull_amount = 0.0;
+        self.action_handler.release_all();
+    }
 
     pub fn handle_character_actions(&mut self, ctx: &egui::Context) {
         let mut set_cursor = false;
+        self.release_stuck_input();
 
         // Execute planned actions
         while let Some(planned_action) = self.planned_actions.pop() {
-            let key_name = self.settings.button_mapping_settings().get(&planned_action.name).unwrap().to_string();
+            self.press_order.retain(|button| button != &planned_action.name);
+            let key_name = if planned_action.just_pressed {
+                let key_name = self.get_mapped_action(&planned_action.name);
+                self.pressed_bindings.insert(planned_action.name.clone(), key_name.clone());
+                self.press_order.push(planned_action.name.clone());
+                key_name
+            } else {
+                match self.pressed_bindings.remove(&planned_action.name) {
+                    Some(key_name) => key_name,
+                    None => continue,
+                }
+            };
             if key_name == "" {continue} // Empty string is how we set keymaps to not taking any action.
             // println!("{key_name}");
+            if self.handle_manager_action(&key_name, planned_action.just_pressed) {continue}
             if planned_action.just_pressed {
-                if planned_action.aimable {
-                    if self.holding_walk && self.holding_aim {
-                        let (new_x, new_y) = self.get_radial_location(self.get_attack_circle_radius(planned_action.distance), self.aiming_angle);
+                // No snapping in cursor mode, actions land wherever the cursor is.
+                if self.control_mode == ControlMode::TwinStick && self.holding_walk && (planned_action.aimable || planned_action.distance != ActionDistance::None) {
+                    let (angle, stick_magnitude) = if planned_action.aimable && self.holding_aim {
+                        (self.aiming_angle, self.aiming_stick_magnitude)
+                    } else {
+                        (self.walking_angle, self.walking_stick_magnitude)
+                    };
+                    if let Some(radius) = self.resolve_action_radius(planned_action.distance, stick_magnitude, ctx) {
+                        let (new_x, new_y) = self.get_radial_location(radius, angle);
                         self.safe_move_mouse(new_x as f64, new_y as f64);
-                        set_cursor = true;
-                    } else if self.holding_walk && !self.holding_aim {
-                        let (new_x, new_y) = self.get_radial_location(self.get_attack_circle_radius(planned_action.distance), self.walking_angle);
-                        self.safe_move_mouse(new_x as f64, new_y as f64);
-                        set_cursor = true;
                     }
-                    // todo probably inject a delay for the two above
-                } else if planned_action.distance != ActionDistance::None && self.holding_walk {
-                        let (new_x, new_y) = self.get_radial_location(self.get_attack_circle_radius(planned_action.distance), self.walking_angle);
-                        self.safe_move_mouse(new_x as f64, new_y as f64);
-                        set_cursor = true;
+                    set_cursor = true;
+                    // todo probably inject a delay for the above
                 }
-                self.action_handler.handle_action(ActionType::Press, key_name);
+                self.action_handler.handle_action(ActionType::Press, key_name.clone());
+                self.track_ui_state(&key_name);
             } else {
                 self.action_handler.handle_action(ActionType::Release, key_name);
             }
         }
 
+        if self.control_mode == ControlMode::Cursor {
+            self.handle_cursor_movement(ctx);
+            return
+        }
+
         // if we're holding an ability but didn't just press something, we need the cursor to swivel if we're also holding a stick.
-        // This block accomplishes that swivel, prioritizing aiming_angle if any held buttons are aimable, and targeting the longest distance
+        // This block accomplishes that swivel, prioritizing aiming_angle if any held buttons are aimable, and targeting the distance picked by held_action_priority
         // If none of the held abilities are aimable or have preset distances, this causes the cursor to snap to the walking circle if held.
         // If none of the held abilities are aimable or have preset distances, AND we're not walking, this lets you free-aim the ability with right stick
         self.holding_ability = self.action_handler.is_ability_key_held();
@@ -144,49 +274,29 @@ impl ActionManager {
                                                                         .collect();
             
             // Check if any of the held actions are aimable, even if they have no action distance set
-            let mut some_held_action_aimable = false;
-            for action in held_ability_actions.clone() {
-                println!("checking if {:?} is aimable", action);
-                if self.settings.aimable_buttons().contains(&action) {
-                    some_held_action_aimable = true;
-                }
-            }
-
-            // Of the abilities with an action distance, check for the farthest distance.
-            let held_abilities_with_action_distance_set = held_ability_actions.into_iter()
-                                                                                    .filter(|action| self.get_ability_action_distance(action) != ActionDistance::None);
-            let mut chosen_distance =  0.0;
-            for (_action, distance) in held_abilities_with_action_distance_set.map(|action| (action.to_owned(), self.get_attack_circle_radius(self.get_ability_action_distance(&action)))) {
-                if distance > chosen_distance {
-                    chosen_distance = distance;
-                }
-            }
-            // println!("{:?},,, {:?}", some_held_action_aimable, chosen_distance);
-            if chosen_distance == 0.0 {
-                // no held ability had a preset distance, use walking distance
-                chosen_distance = self.settings.controller_settings().walk_circle_radius_px();
-            }
-            if some_held_action_aimable && self.holding_aim {
-                let (new_x, new_y) = self.get_radial_location(chosen_distance, self.aiming_angle);
-                self.safe_move_mouse(new_x as f64, new_y as f64);
-                set_cursor = true;
+            let some_held_action_aimable = held_ability_actions.iter().any(|action| self.settings.aimable_buttons().contains(action));
+            let (angle, stick_magnitude) = if some_held_action_aimable && self.holding_aim {
+                (self.aiming_angle, self.aiming_stick_magnitude)
             } else {
-                let (new_x, new_y) = self.get_radial_location(chosen_distance, self.walking_angle);
+                (self.walking_angle, self.walking_stick_magnitude)
+            };
+            if let Some(chosen_distance) = self.choose_held_action_radius(&held_ability_actions, stick_magnitude, ctx) {
+                let (new_x, new_y) = self.get_radial_location(chosen_distance, angle);
                 self.safe_move_mouse(new_x as f64, new_y as f64);
-                set_cursor = true;
             }
+            set_cursor = true;
         }
         
         // if aiming and not moving!
         if self.holding_aim && !self.holding_walk {
-            let (new_x_pos, new_y_pos) = self.get_free_move_update(ctx);
+            let (new_x_pos, new_y_pos) = self.get_free_move_update(ctx, &self.aiming_stick_direction, self.settings.controller_settings().free_mouse_sensitivity_px());
             self.safe_move_mouse(new_x_pos, new_y_pos);
             set_cursor = true;
         } 
 
         // if moving!
         if self.holding_walk && !set_cursor {
-            let (new_x, new_y) = self.get_radial_location(self.settings.controller_settings().walk_circle_radius_px(), self.walking_angle);
+            let (new_x, new_y) = self.get_radial_location(self.settings.controller_settings().walk_circle_radius_px(self.game_window_tracker.borrow().game_window_height()), self.walking_angle);
             self.safe_move_mouse(new_x as f64, new_y as f64);
         }
         if self.holding_walk {
@@ -197,28 +307,139 @@ impl ActionManager {
   
     }
 
-    fn safe_move_mouse(&self, new_x: f64, new_y: f64) {
-        if self.game_window_tracker.windowed_mode() {
-            let (new_safe_x, new_safe_y) = self.get_window_bounded_position(new_x, new_y);
-            self.action_handler.move_mouse(new_safe_x, new_safe_y);
-        } else {
-            self.action_handler.move_mouse(new_x as f64, new_y as f64);
+    // Watchdog for input that outlived its button, e.g. when a release event never arrived.
+    // Anything held longer than max_key_hold_ms without a held controller button behind it gets released.
+    fn release_stuck_input(&mut self) {
+        let max_key_hold_ms = self.settings.input_safety_settings().max_key_hold_ms();
+        if max_key_hold_ms == 0 {
+            return
+        }
+        let mut expected_actions: HashSet<String> = self.pressed_bindings.iter()
+                                                        .filter(|(button, _key_name)| self.held_buttons.contains(*button))
+                                                        .map(|(_button, key_name)| key_name.to_lowercase())
+                                                        // Modifier clicks hold left click once the modifier is let go
+                                                        .map(|key_name| if key_name.ends_with("leftclick") {"leftclick".to_owned()} else {key_name})
+                                                        .collect();
+        if self.holding_walk {
+            expected_actions.insert("leftclick".to_owned());
+        }
+        for held_action in self.action_handler.actions_held_longer_than(Duration::from_millis(max_key_hold_ms)) {
+            if !expected_actions.contains(&held_action) {
+                println!("Releasing {:?}, it was held over {}ms without its button", held_action, max_key_hold_ms);
+                self.action_handler.release_held_action(&held_action);
+            }
+        }
+    }
+
+    fn handle_cursor_movement(&mut self, ctx: &egui::Context) {
+        // Left stick drives the cursor, right stick keeps its free-aim behaviour for finer adjustments
+        if self.holding_walk {
+            let (new_x_pos, new_y_pos) = self.get_free_move_update(ctx, &self.walking_stick_direction, self.settings.cursor_mode_settings().cursor_speed_px());
+            self.safe_move_mouse(new_x_pos, new_y_pos);
+        } else if self.holding_aim {
+            let (new_x_pos, new_y_pos) = self.get_free_move_update(ctx, &self.aiming_stick_direction, self.settings.controller_settings().free_mouse_sensitivity_px());
+            self.safe_move_mouse(new_x_pos, new_y_pos);
         }
     }
+
+    // Some bindings drive the action manager itself rather than sending anything to the game.
+    // Returns true if key_name was one of them, they only act on press.
+    fn handle_manager_action(&mut self, key_name: &String, just_pressed: bool) -> bool {
+        let key_lower = key_name.to_lowercase();
+        let is_manager_action = matches!(key_lower.as_str(), "togglecursormode" | "resetuistate" | "cyclestashtab" | "gridup" | "griddown" | "gridleft" | "gridright");
+        if is_manager_action && just_pressed {
+            match key_lower.as_str() {
+                "togglecursormode" => self.toggle_control_mode(),
+                "resetuistate" => {
+                    self.ui_state_tracker.force_state(UiState::InGame);
+                    self.set_control_mode(ControlMode::TwinStick);
+                },
+                "cyclestashtab" => self.grid_navigator.cycle_stash_tab(),
+                "gridup" => self.step_grid_cursor(GridDirection::Up),
+                "griddown" => self.step_grid_cursor(GridDirection::Down),
+                "gridleft" => self.step_grid_cursor(GridDirection::Left),
+                "gridright" => self.step_grid_cursor(GridDirection::Right),
+                _ => (),
+            }
+        }
+        is_manager_action
+    }
+
+    fn step_grid_cursor(&mut self, direction: GridDirection) {
+        self.grid_navigator.step(direction);
+        let (new_x, new_y) = self.grid_navigator.cell_center(&self.game_window_tracker.borrow());
+        self.safe_move_mouse(new_x as f64, new_y as f64);
+    }
+
+    fn track_ui_state(&mut self, key_name: &String) {
+        if let Some(ui_state) = self.ui_state_tracker.key_sent(key_name) {
+            if self.settings.ui_state_settings().automatic_mode_switching() {
+                match ui_state {
+                    UiState::InGame => self.set_control_mode(ControlMode::TwinStick),
+                    UiState::InventoryOpen | UiState::PanelOpen => self.set_control_mode(ControlMode::Cursor),
+                }
+            }
+        }
+    }
+
+    fn toggle_control_mode(&mut self) {
+        // A manual toggle is also how the user corrects the tracked UI state when it drifts
+        match self.control_mode {
+            ControlMode::TwinStick => {
+                if self.ui_state_tracker.state() == UiState::InGame {
+                    self.ui_state_tracker.force_state(UiState::PanelOpen);
+                }
+                self.set_control_mode(ControlMode::Cursor);
+            },
+            ControlMode::Cursor => {
+                self.ui_state_tracker.force_state(UiState::InGame);
+                self.set_control_mode(ControlMode::TwinStick);
+            },
+        }
+    }
+
+    fn set_control_mode(&mut self, control_mode: ControlMode) {
+        if self.control_mode == control_mode {
+            return
+        }
+        self.control_mode = control_mode;
+        // Walking holds left click down, which would drag items around once the cursor is free
+        if !self.action_handler.holding_left_click_for_action() {
+            self.action_handler.handle_action(ActionType::Release, "leftclick".to_string());
+        }
+        println!("Switched to {:?} control mode", self.control_mode);
+    }
+
+    fn get_mapped_action(&self, button_name: &String) -> String {
+        // Cursor mode bindings take priority, anything unbound there keeps its regular binding
+        if self.control_mode == ControlMode::Cursor {
+            if let Some(key_name) = self.settings.cursor_mode_settings().button_mapping().get(button_name) {
+                return key_name.to_string();
+            }
+        }
+        self.settings.button_mapping_settings().get(button_name).unwrap().to_string()
+    }
+
+    fn safe_move_mouse(&mut self, new_x: f64, new_y: f64) {
+        let (new_safe_x, new_safe_y) = if self.game_window_tracker.borrow().windowed_mode() {
+            self.get_window_bounded_position(new_x, new_y)
+        } else {
+            (new_x, new_y)
+        };
+        self.action_handler.move_mouse(new_safe_x, new_safe_y);
+        self.last_cursor_target = Some((new_safe_x, new_safe_y));
+    }
     
     fn get_window_bounded_position(&self, new_x: f64, new_y: f64) -> (f64, f64) {
         let mut return_x = new_x;
         let mut return_y = new_y;
 
-        #[cfg(target_os = "linux")]
-        let (title_bar_height, window_shadow_amount) = (2.0, 2.0); // magic numbers for linux
-        #[cfg(target_os = "windows")]
-        let (title_bar_height, window_shadow_amount) = (32.0, 10.0); // magic numbers, may only be correct on windows
-
-        let min_x_pos = (self.game_window_tracker.window_pos_x() + window_shadow_amount) as f64;
-        let min_y_pos = (self.game_window_tracker.window_pos_y() + title_bar_height) as f64;
-        let max_x_pos = (self.game_window_tracker.window_pos_x() + self.game_window_tracker.game_window_width() - window_shadow_amount) as f64;
-        let max_y_pos = (self.game_window_tracker.window_pos_y() + self.game_window_tracker.game_window_height()- window_shadow_amount) as f64;
+        // The tracker already excludes the title bar and borders, so this keeps clicks inside the client area
+        let game_window_tracker = self.game_window_tracker.borrow();
+        let min_x_pos = game_window_tracker.window_pos_x() as f64;
+        let min_y_pos = game_window_tracker.window_pos_y() as f64;
+        let max_x_pos = (game_window_tracker.window_pos_x() + game_window_tracker.game_window_width() - 1.0) as f64;
+        let max_y_pos = (game_window_tracker.window_pos_y() + game_window_tracker.game_window_height() - 1.0) as f64;
         if new_x < min_x_pos {
             return_x = min_x_pos;
         } else if new_x > max_x_pos {
@@ -234,49 +455,121 @@ impl ActionManager {
 
 
     fn get_radial_location(&self, circle_radius: f32, angle: f32) -> (f32, f32) {
-        let screen_adjustment_x = angle.cos() * circle_radius;
-        let screen_adjustment_y = angle.sin() * circle_radius;
-        let new_x = self.game_window_tracker.game_window_width()/2.0 + screen_adjustment_x + self.settings.controller_settings().character_x_offset_px() + self.game_window_tracker.window_pos_x();
-        let new_y = self.game_window_tracker.game_window_height()/2.0 - screen_adjustment_y - self.settings.controller_settings().character_y_offset_px() + self.game_window_tracker.window_pos_y();
+        let (screen_adjustment_x, screen_adjustment_y) = AimRing::new(circle_radius, &self.settings.controller_settings()).offset_at(angle);
+        let game_window_height = self.game_window_tracker.borrow().game_window_height();
+        let new_x = self.game_window_tracker.borrow().game_window_width()/2.0 + screen_adjustment_x + self.settings.controller_settings().character_x_offset_px(game_window_height) + self.game_window_tracker.borrow().window_pos_x();
+        let new_y = game_window_height/2.0 - screen_adjustment_y - self.settings.controller_settings().character_y_offset_px(game_window_height) + self.game_window_tracker.borrow().window_pos_y();
         (new_x, new_y)
     }
 
-    fn get_attack_circle_radius(&self, action_distance: ActionDistance) -> f32 {
+    // Returns None when the action should land wherever the cursor already is
+    fn resolve_action_radius(&self, action_distance: ActionDistance, stick_magnitude: f32, ctx: &egui::Context) -> Option<f32> {
+        let game_window_height = self.game_window_tracker.borrow().game_window_height();
         match action_distance {
-            ActionDistance::Close => {self.settings.controller_settings().close_circle_radius_px()},
-            ActionDistance::Mid => {self.settings.controller_settings().mid_circle_radius_px()},
-            ActionDistance::Far => {self.settings.controller_settings().far_circle_radius_px()},
-            _ => {self.settings.controller_settings().walk_circle_radius_px()}
+            ActionDistance::Close => Some(self.settings.controller_settings().close_circle_radius_px(game_window_height)),
+            ActionDistance::Mid => Some(self.settings.controller_settings().mid_circle_radius_px(game_window_height)),
+            ActionDistance::Far => Some(self.settings.controller_settings().far_circle_radius_px(game_window_height)),
+            ActionDistance::Radius(radius) => Some(radius.resolve(game_window_height)),
+            ActionDistance::StickMagnitude { min, max } => {
+                let (min, max) = (min.resolve(game_window_height), max.resolve(game_window_height));
+                Some(min + (max - min) * stick_magnitude)
+            },
+            ActionDistance::ClampCursor { min, max } => {
+                // min and max can be in different units, so don't trust their order enough for f32::clamp
+                Some(self.get_cursor_distance_from_character(ctx).max(min.resolve(game_window_height)).min(max.resolve(game_window_height)))
+            },
+            ActionDistance::Cursor => None,
+            ActionDistance::None => Some(self.settings.controller_settings().walk_circle_radius_px(game_window_height)),
+        }
+    }
+
+    // Picks the cursor distance while several abilities are held, according to held_action_priority
+    fn choose_held_action_radius(&self, held_ability_actions: &Vec<String>, stick_magnitude: f32, ctx: &egui::Context) -> Option<f32> {
+        let held_actions_with_distance: Vec<(&String, ActionDistance)> = held_ability_actions.iter()
+                                                                                .map(|action| (action, self.get_ability_action_distance(action)))
+                                                                                .filter(|(_action, distance)| *distance != ActionDistance::None)
+                                                                                .collect();
+        if held_actions_with_distance.is_empty() {
+            // no held ability had a preset distance, use walking distance
+            return Some(self.settings.controller_settings().walk_circle_radius_px(self.game_window_tracker.borrow().game_window_height()));
+        }
+
+        let chosen_action = match self.settings.held_action_priority() {
+            HeldActionPriority::Farthest => {
+                // Casting at the cursor has no fixed reach, so it beats any radius
+                let radii: Vec<Option<f32>> = held_actions_with_distance.iter()
+                                                    .map(|(_action, distance)| self.resolve_action_radius(*distance, stick_magnitude, ctx))
+                                                    .collect();
+                if radii.contains(&None) {
+                    return None;
+                }
+                return radii.into_iter().flatten().reduce(f32::max);
+            },
+            HeldActionPriority::MostRecent => {
+                held_actions_with_distance.iter()
+                    .max_by_key(|(action, _distance)| self.press_order.iter().position(|button| button == *action))
+            },
+            HeldActionPriority::Priority => {
+                let ability_priority = self.settings.ability_priority();
+                held_actions_with_distance.iter()
+                    .min_by_key(|(action, _distance)| ability_priority.iter().position(|button| button == *action).unwrap_or(usize::MAX))
+            },
+        };
+        // held_actions_with_distance isn't empty, so there is always a chosen action
+        let (_action, distance) = chosen_action.unwrap();
+        self.resolve_action_radius(*distance, stick_magnitude, ctx)
+    }
+
+    fn get_cursor_distance_from_character(&self, ctx: &egui::Context) -> f32 {
+        let (character_x, character_y) = self.get_radial_location(0.0, 0.0);
+        match self.get_cursor_position(ctx) {
+            // Measured along the ring shape, so clamping keeps the cursor between the same rings the overlay draws
+            Some((cursor_x, cursor_y)) => AimRing::new(1.0, &self.settings.controller_settings()).radius_through(cursor_x - character_x, character_y - cursor_y),
+            None => 0.0,
         }
     }
 
-    fn get_free_move_update(&self, ctx: &egui::Context) -> (f64, f64){
-        let screen_adjustment_x = self.aiming_stick_direction[0] * self.settings.controller_settings().free_mouse_sensitivity_px() ;
-        let screen_adjustment_y = -1.0 * self.aiming_stick_direction[1] * self.settings.controller_settings().free_mouse_sensitivity_px();
+    // egui reports the pointer in overlay points, everything here works in global desktop coordinates
+    fn get_cursor_position(&self, ctx: &egui::Context) -> Option<(f32, f32)> {
+        let hover_pos = ctx.input().pointer.hover_pos();
+        let screen_coordinates = self.game_window_tracker.borrow().screen_coordinates();
+        hover_pos.map(|position| screen_coordinates.to_desktop(position.x, position.y))
+    }
+
+    fn get_free_move_update(&self, ctx: &egui::Context, stick_direction: &Vec<f32>, sensitivity_px: f32) -> (f64, f64){
+        let screen_adjustment_x = stick_direction[0] * sensitivity_px;
+        let screen_adjustment_y = -1.0 * stick_direction[1] * sensitivity_px;
         // There is a chance that there _is_ no mouse position.
-        match ctx.input().pointer.hover_pos() {
-            Some(position) => ((position.x + screen_adjustment_x) as f64, (position.y + screen_adjustment_y) as f64),
+        match self.get_cursor_position(ctx) {
+            Some((cursor_x, cursor_y)) => ((cursor_x + screen_adjustment_x) as f64, (cursor_y + screen_adjustment_y) as f64),
             // Should we just panic here?
             None => (0.0f64, 0.0f64),
         }
     }
 
     fn get_ability_action_distance(&self, name: &String) -> ActionDistance {
-        if self.settings.action_distances().contains_key(name) {
-            // println!("herp {:?}", name);
-            match self.settings.action_distances().get(name).unwrap().as_str() {
-                "close" => {ActionDistance::Close},
-                "mid" => {ActionDistance::Mid},
-                "far" => {ActionDistance::Far},
-                _ => {ActionDistance::None}
-            }
-        } else {
-            // println!("derp {:?}", name);
-            ActionDistance::None}
+        match self.settings.action_distances().get(name) {
+            Some(ActionDistanceSetting::Named(distance)) => {
+                match distance.as_str() {
+                    "close" => {ActionDistance::Close},
+                    "mid" => {ActionDistance::Mid},
+                    "far" => {ActionDistance::Far},
+                    "cursor" => {ActionDistance::Cursor},
+                    radius => {ScreenDistance::parse(radius).map_or(ActionDistance::None, ActionDistance::Radius)},
+                }
+            },
+            Some(ActionDistanceSetting::Radius(radius)) => ActionDistance::Radius(ScreenDistance::Pixels(*radius)),
+            Some(ActionDistanceSetting::Range { min, max, policy }) => {
+                match policy {
+                    RangePolicy::StickMagnitude => ActionDistance::StickMagnitude { min: *min, max: *max },
+                    RangePolicy::ClampCursor => ActionDistance::ClampCursor { min: *min, max: *max },
+                }
+            },
+            None => ActionDistance::None,
+        }
     } 
 }
 
 
 
 
-
@@ -0,0 +1,40 @@
+use crate::settings::ControllerSettings;
+
+// PoE's tilted camera makes vertical screen distance cover more ground than horizontal,
+// so the walk and action rings are ellipses rather than circles.
+// Offsets are relative to the character with y pointing up, like the sticks.
+#[derive(Clone, Copy)]
+pub struct AimRing {
+    radius: f32,
+    aspect_ratio: f32,
+    rotation: f32,
+}
+
+impl AimRing {
+    pub fn new(radius: f32, controller_settings: &ControllerSettings) -> AimRing {
+        AimRing {
+            radius: radius,
+            aspect_ratio: controller_settings.ring_aspect_ratio(),
+            rotation: controller_settings.ring_rotation_degrees().to_radians(),
+        }
+    }
+
+    pub fn offset_at(&self, angle: f32) -> (f32, f32) {
+        let local_x = angle.cos() * self.radius;
+        let local_y = angle.sin() * self.radius * self.aspect_ratio;
+        (local_x * self.rotation.cos() - local_y * self.rotation.sin(),
+         local_x * self.rotation.sin() + local_y * self.rotation.cos())
+    }
+
+    // Radius of a ring with this shape that passes through the given offset
+    pub fn radius_through(&self, offset_x: f32, offset_y: f32) -> f32 {
+        let local_x = offset_x * self.rotation.cos() + offset_y * self.rotation.sin();
+        let local_y = -offset_x * self.rotation.sin() + offset_y * self.rotation.cos();
+        (local_x.powi(2) + (local_y / self.aspect_ratio).powi(2)).sqrt()
+    }
+
+    pub fn outline(&self, segments: usize) -> Vec<(f32, f32)> {
+        (0..segments).map(|segment| self.offset_at(segment as f32 / segments as f32 * std::f32::consts::TAU))
+                     .collect()
+    }
+}
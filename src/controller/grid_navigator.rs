@@ -0,0 +1,110 @@
+use crate::game_window_tracker::GameWindowTracker;
+use crate::settings::{GridNavigationSettings, InventoryGridSettings};
+
+#[derive(Copy, Clone, PartialEq, Debug, serde::Deserialize)]
+pub enum StashTabType {
+    Standard,
+    Quad,
+    Currency,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum GridDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// The stash sits on the left half of the screen and the inventory on the right, so walking off their shared edge swaps panels
+#[derive(Copy, Clone, PartialEq)]
+enum GridPanel {
+    Stash,
+    Inventory,
+}
+
+pub struct GridNavigator {
+    settings: GridNavigationSettings,
+    stash_tab: StashTabType,
+    panel: GridPanel,
+    column: u32,
+    row: u32,
+}
+
+impl GridNavigator {
+    pub fn new(settings: GridNavigationSettings) -> GridNavigator {
+        GridNavigator {
+            stash_tab: settings.stash_tab(),
+            settings: settings,
+            panel: GridPanel::Inventory,
+            column: 0,
+            row: 0,
+        }
+    }
+
+    pub fn step(&mut self, direction: GridDirection) {
+        let grid = self.current_grid();
+        match direction {
+            GridDirection::Up => {self.row = self.row.saturating_sub(1)},
+            GridDirection::Down => {self.row = (self.row + 1).min(grid.rows() - 1)},
+            GridDirection::Left => {
+                if self.column > 0 {
+                    self.column -= 1;
+                } else if self.panel == GridPanel::Inventory {
+                    self.switch_panel(GridPanel::Stash);
+                }
+            },
+            GridDirection::Right => {
+                if self.column + 1 < grid.columns() {
+                    self.column += 1;
+                } else if self.panel == GridPanel::Stash {
+                    self.switch_panel(GridPanel::Inventory);
+                }
+            },
+        }
+    }
+
+    pub fn cycle_stash_tab(&mut self) {
+        self.stash_tab = match self.stash_tab {
+            StashTabType::Standard => StashTabType::Quad,
+            StashTabType::Quad => StashTabType::Currency,
+            StashTabType::Currency => StashTabType::Standard,
+        };
+        println!("Grid navigation using {:?} stash tab", self.stash_tab);
+        if self.panel == GridPanel::Stash {
+            let grid = self.current_grid();
+            self.column = self.column.min(grid.columns() - 1);
+            self.row = self.row.min(grid.rows() - 1);
+        }
+    }
+
+    pub fn cell_center(&self, game_window_tracker: &GameWindowTracker) -> (f32, f32) {
+        let grid = self.current_grid();
+        let x = game_window_tracker.game_window_width() * (grid.origin_x() + (self.column as f32 + 0.5) * grid.cell_width()) + game_window_tracker.window_pos_x();
+        let y = game_window_tracker.game_window_height() * (grid.origin_y() + (self.row as f32 + 0.5) * grid.cell_height()) + game_window_tracker.window_pos_y();
+        (x, y)
+    }
+
+    fn switch_panel(&mut self, panel: GridPanel) {
+        let previous_rows = self.current_grid().rows();
+        self.panel = panel;
+        let grid = self.current_grid();
+        // Keep roughly the same height on screen, the panels don't have the same number of rows
+        self.row = (self.row * grid.rows() / previous_rows).min(grid.rows() - 1);
+        self.column = match panel {
+            GridPanel::Stash => grid.columns() - 1,
+            GridPanel::Inventory => 0,
+        };
+    }
+
+    fn current_grid(&self) -> InventoryGridSettings {
+        match self.panel {
+            GridPanel::Inventory => self.settings.inventory(),
+            GridPanel::Stash => match self.stash_tab {
+                StashTabType::Standard => self.settings.standard_stash(),
+                StashTabType::Quad => self.settings.quad_stash(),
+                StashTabType::Currency => self.settings.currency_stash(),
+            },
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::HashMap, env, fs};
 
-use gilrs::{Gilrs, GamepadId, Axis, Button, Event, EventType};
+use gilrs::{Gilrs, GilrsBuilder, GamepadId, Axis, Button, Event, EventType, MappingSource};
+
+use crate::settings::ControllerSettings;
 
 #[derive(Default)]
 pub struct ControllerButton {
@@ -22,6 +24,7 @@ impl ControllerButton {
 #[derive(Default)]
 struct TriggerButton {
     trigger_threshold: f32,
+    value: f32,
     button: ControllerButton,
 }
 
@@ -30,7 +33,13 @@ impl TriggerButton {
         self.trigger_threshold = value;
     }
 
+    // A trigger remapped onto a regular button has to be pulled just as far to press it
+    fn thresholded_value(&self, value: f32) -> f32 {
+        if value >= self.trigger_threshold {1.0} else {0.0}
+    }
+
     fn changed_button_event(&mut self, value: f32) {
+        self.value = value;
         let is_trigger_button_pressed = value >= self.trigger_threshold;
         // Need to or these because trigger buttonchange events can collect more than once per frame
         // So if just_un/pressed gets set to true, we need to preserve that long enough to call process_input_events
@@ -55,18 +64,29 @@ impl AnalogStickWithButton {
 pub struct AnalogStick  {
 	stick_x: f32,
     stick_y: f32,
+    // Before the deadzone, for the controller tester
+    raw_stick_x: f32,
+    raw_stick_y: f32,
     deadzone: f32,
 }
 
 impl AnalogStick {
     fn changed_axis_event(&mut self, value: f32, is_stick_x: bool) {
         if is_stick_x {
+            self.raw_stick_x = value;
             self.stick_x = self.compute_joystick_deadzone(value);
         } else {
+            self.raw_stick_y = value;
             self.stick_y = self.compute_joystick_deadzone(value);
         }
     }
 
+    pub fn raw_stick_direction(&self) -> Vec<f32> {
+        vec![self.raw_stick_x, self.raw_stick_y]
+    }
+
+    pub fn deadzone(&self) -> f32 {self.deadzone}
+
     fn set_joystick_deadzone(&mut self, value: f32) {
         self.deadzone = value;
     }
@@ -85,6 +105,10 @@ impl AnalogStick {
         vec![self.stick_x, self.stick_y]
     }
 
+    pub fn stick_magnitude(&self) -> f32 {
+        (self.stick_x * self.stick_x + self.stick_y * self.stick_y).sqrt().min(1.0)
+    }
+
     pub fn stick_angle(&self) -> f32 {
         self.stick_y.atan2(self.stick_x)
     }
@@ -156,6 +180,30 @@ impl ControllerState {
         buttons
     }
 
+    // Drops presses and releases nobody is going to act on, so they don't fire later
+    pub fn clear_button_events(&mut self) {
+        for button in self.get_all_buttons().values_mut() {
+            button.just_pressed = false;
+            button.just_unpressed = false;
+        }
+    }
+
+    // Forgets everything the last controller had held, keeping trigger thresholds and deadzones
+    pub fn clear_input(&mut self) {
+        for button in self.get_all_buttons().values_mut() {
+            **button = ControllerButton::default();
+        }
+        for analog in [&mut self.left_analog, &mut self.right_analog] {
+            analog.analog_stick.stick_x = 0.0;
+            analog.analog_stick.stick_y = 0.0;
+            analog.analog_stick.raw_stick_x = 0.0;
+            analog.analog_stick.raw_stick_y = 0.0;
+        }
+        for trigger in [&mut self.trigger_left, &mut self.trigger_right] {
+            trigger.value = 0.0;
+        }
+    }
+
     pub fn get_left_analog_stick(&self) -> AnalogStick {
         self.left_analog.analog_stick()
     }
@@ -164,43 +212,238 @@ impl ControllerState {
         self.right_analog.analog_stick()
     }
 
+    // How far each trigger is pulled, and how far it has to be pulled to count as pressed
+    pub fn get_left_trigger(&self) -> (f32, f32) {
+        (self.trigger_left.value, self.trigger_left.trigger_threshold)
+    }
+
+    pub fn get_right_trigger(&self) -> (f32, f32) {
+        (self.trigger_right.value, self.trigger_right.trigger_threshold)
+    }
+
 }
 
-#[derive(Copy, Clone, serde::Deserialize)]
+#[derive(Copy, Clone, Debug, serde::Deserialize)]
 pub enum ControllerType {
     Playstation,
     Xbox,
+    Nintendo,
+    Steam,
+    Generic,
 }
 
+// USB vendor and product IDs, from http://www.linux-usb.org/usb.ids
+const SONY_VENDOR_ID: u16 = 0x054c;
+const MICROSOFT_VENDOR_ID: u16 = 0x045e;
+const NINTENDO_VENDOR_ID: u16 = 0x057e;
+const VALVE_VENDOR_ID: u16 = 0x28de;
+// Steam Input's virtual pad pretends to be an Xbox 360 controller
+const STEAM_VIRTUAL_GAMEPAD_PRODUCT_ID: u16 = 0x11ff;
+
 #[derive(Copy, Clone, serde::Deserialize)]
 pub enum ControllerTypeDetection {
     Auto,
     Forced(ControllerType),
 }
 
+// Sits between gilrs and ControllerState, so the rest of the app only sees the buttons after remapping.
+// Every option is a swap or a flip, so the same mapping works in both directions.
+#[derive(Copy, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct ButtonRemap {
+    // A with B and X with Y, the Nintendo layout
+    swap_face_buttons: bool,
+    // Left stick (and L3) with right stick (and R3)
+    swap_sticks: bool,
+    swap_bumpers_and_triggers: bool,
+    invert_left_stick_x: bool,
+    invert_left_stick_y: bool,
+    invert_right_stick_x: bool,
+    invert_right_stick_y: bool,
+}
+
+impl ButtonRemap {
+    fn remap_button(&self, button: Button) -> Button {
+        let button = match button {
+            Button::South if self.swap_face_buttons => Button::East,
+            Button::East if self.swap_face_buttons => Button::South,
+            Button::West if self.swap_face_buttons => Button::North,
+            Button::North if self.swap_face_buttons => Button::West,
+            _ => button,
+        };
+        let button = match button {
+            Button::LeftThumb if self.swap_sticks => Button::RightThumb,
+            Button::RightThumb if self.swap_sticks => Button::LeftThumb,
+            _ => button,
+        };
+        match button {
+            Button::LeftTrigger if self.swap_bumpers_and_triggers => Button::LeftTrigger2,
+            Button::LeftTrigger2 if self.swap_bumpers_and_triggers => Button::LeftTrigger,
+            Button::RightTrigger if self.swap_bumpers_and_triggers => Button::RightTrigger2,
+            Button::RightTrigger2 if self.swap_bumpers_and_triggers => Button::RightTrigger,
+            _ => button,
+        }
+    }
+
+    // Inverting applies to the stick after swapping, so invert_left_stick_x always means the stick that walks
+    fn remap_axis(&self, axis: Axis, value: f32) -> (Axis, f32) {
+        let axis = match axis {
+            Axis::LeftStickX if self.swap_sticks => Axis::RightStickX,
+            Axis::LeftStickY if self.swap_sticks => Axis::RightStickY,
+            Axis::RightStickX if self.swap_sticks => Axis::LeftStickX,
+            Axis::RightStickY if self.swap_sticks => Axis::LeftStickY,
+            _ => axis,
+        };
+        let inverted = match axis {
+            Axis::LeftStickX => self.invert_left_stick_x,
+            Axis::LeftStickY => self.invert_left_stick_y,
+            Axis::RightStickX => self.invert_right_stick_x,
+            Axis::RightStickY => self.invert_right_stick_y,
+            _ => false,
+        };
+        (axis, if inverted {-value} else {value})
+    }
+
+    // Which physical button drives a ControllerState button, so the overlay can show its glyph.
+    // Also understands "left_stick" and "right_stick" for the sticks themselves.
+    pub fn physical_button(&self, button_name: &str) -> String {
+        let button_name = match button_name {
+            "a" if self.swap_face_buttons => "b",
+            "b" if self.swap_face_buttons => "a",
+            "x" if self.swap_face_buttons => "y",
+            "y" if self.swap_face_buttons => "x",
+            "bumper_left" if self.swap_bumpers_and_triggers => "trigger_left",
+            "trigger_left" if self.swap_bumpers_and_triggers => "bumper_left",
+            "bumper_right" if self.swap_bumpers_and_triggers => "trigger_right",
+            "trigger_right" if self.swap_bumpers_and_triggers => "bumper_right",
+            _ => button_name,
+        };
+        match button_name {
+            "left_analog" if self.swap_sticks => "right_analog",
+            "right_analog" if self.swap_sticks => "left_analog",
+            "left_stick" if self.swap_sticks => "right_stick",
+            "right_stick" if self.swap_sticks => "left_stick",
+            _ => button_name,
+        }.to_owned()
+    }
+}
+
+// What happens when the controller in use disconnects while controller input is running
+#[derive(Copy, Clone, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconnectPolicy {
+    SameController,
+    AnyController,
+    StayPaused,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {ReconnectPolicy::SameController}
+}
+
+// Paths to more gamecontrollerdb.txt files, separated like PATH
+const MAPPING_FILES_ENV_VAR: &str = "EXILE_CONTROLLER_MAPPINGS";
+// gilrs reads mappings themselves (not paths) from here, like SDL does
+const SDL_MAPPINGS_ENV_VAR: &str = "SDL_GAMECONTROLLERCONFIG";
+// gilrs skips mappings whose platform field names a different platform than these
+#[cfg(target_os = "linux")]
+const SDL_PLATFORM_NAME: &str = "Linux";
+#[cfg(target_os = "macos")]
+const SDL_PLATFORM_NAME: &str = "Mac OS X";
+#[cfg(windows)]
+const SDL_PLATFORM_NAME: &str = "Windows";
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const SDL_PLATFORM_NAME: &str = "Unknown";
+
+// A gamecontrollerdb.txt we loaded, and the names of the mappings it has by controller UUID
+struct MappingFile {
+    path: String,
+    map_names: HashMap<String, String>,
+}
+
+impl MappingFile {
+    fn new(path: String, mappings: &str) -> MappingFile {
+        MappingFile { path: path, map_names: mapping_names(mappings) }
+    }
+}
+
+// Each mapping line starts with the controller's UUID as hex (the same format as controller_uuid), then its name.
+// Like in gilrs, lines for other platforms are skipped and a later line for the same controller wins.
+fn mapping_names(mappings: &str) -> HashMap<String, String> {
+    mappings.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter(|line| line.split(',').filter_map(|field| field.strip_prefix("platform:")).all(|platform| platform == SDL_PLATFORM_NAME))
+            .filter_map(|line| {
+                let mut fields = line.split(',');
+                Some((fields.next()?.to_lowercase(), fields.next()?.to_owned()))
+            })
+            .collect()
+}
+
+fn load_mapping_files(controller_settings: &ControllerSettings) -> Vec<(String, String)> {
+    let env_paths: Vec<String> = env::var_os(MAPPING_FILES_ENV_VAR)
+                                    .map(|paths| env::split_paths(&paths).map(|path| path.to_string_lossy().into_owned()).collect())
+                                    .unwrap_or_default();
+    controller_settings.mapping_files().into_iter()
+                        .chain(env_paths)
+                        .filter_map(|path| match fs::read_to_string(&path) {
+                            Ok(mappings) => {
+                                println!("Loaded controller mappings from {}", path);
+                                Some((path, mappings))
+                            },
+                            Err(error) => {
+                                println!("Unable to load controller mappings from {}: {}", path, error);
+                                None
+                            },
+                        })
+                        .collect()
+}
+
 pub struct GamepadManager {
     gilrs_context: Gilrs,
+    mapping_files: Vec<MappingFile>,
     gamepad_id: Option<GamepadId>,
+    // UUID of the controller picked in the remote, connected to over anything else when it's plugged in
+    preferred_controller: Option<String>,
+    // UUID of the controller that disconnected last, so it's the one picked when it comes back
+    lost_controller: Option<String>,
+    // Remaps by controller, looked up whenever one connects
+    controller_settings: ControllerSettings,
+    button_remap: ButtonRemap,
     controller_type: Option<ControllerType>,
     pub controller_type_detection: ControllerTypeDetection,
     pub controller_state: ControllerState,
 }
 
-pub fn load_gamepad_manager(analog_deadzone: f32) -> GamepadManager {
-    let gilrs = Gilrs::new().unwrap();
+pub fn load_gamepad_manager(controller_settings: ControllerSettings) -> GamepadManager {
+    let mapping_files = load_mapping_files(&controller_settings);
+    let mut gilrs_builder = GilrsBuilder::new()
+                                .add_included_mappings(controller_settings.bundled_mappings())
+                                .add_env_mappings(true);
+    for (_, mappings) in &mapping_files {
+        gilrs_builder = gilrs_builder.add_mappings(mappings);
+    }
+    let gilrs = gilrs_builder.build().unwrap();
+    let analog_deadzone = controller_settings.controller_deadzone();
+    let controller_type_detection = controller_settings.controller_type();
 
     let mut gamepad_manager = GamepadManager{
         gilrs_context: gilrs,
+        mapping_files: mapping_files.into_iter().map(|(path, mappings)| MappingFile::new(path, &mappings)).collect(),
         gamepad_id: None,
+        preferred_controller: controller_settings.preferred_controller(),
+        lost_controller: None,
+        controller_settings: controller_settings,
+        button_remap: ButtonRemap::default(),
         controller_type: None,
-        controller_type_detection: ControllerTypeDetection::Auto,
+        controller_type_detection: controller_type_detection,
         controller_state: ControllerState::default(),
     };
 
     // Gilrs will not initially throw a connected event if a controller is connected from the start
-    let connected_controllers = gamepad_manager.get_connected_controllers();
-    if !connected_controllers.is_empty() {
-        gamepad_manager.connect_to_controller(connected_controllers, 0);
+    if let Some(gamepad_id) = gamepad_manager.choose_controller() {
+        gamepad_manager.connect_to_controller(gamepad_id);
     }
 
     // initialize triggers and joy stick deadzones
@@ -223,7 +466,13 @@ impl GamepadManager {
                             EventType::Disconnected => self.disconnect_connected_controller(),
                             EventType::ButtonChanged(button, value, _code) => {
                                 //println!("Button Changed! {:?}: {value} : {code}!", button);
-                                match button {
+                                let remapped_button = self.button_remap.remap_button(button);
+                                let value = match button {
+                                    Button::LeftTrigger2 if remapped_button != button => self.controller_state.trigger_left.thresholded_value(value),
+                                    Button::RightTrigger2 if remapped_button != button => self.controller_state.trigger_right.thresholded_value(value),
+                                    _ => value,
+                                };
+                                match remapped_button {
                                     Button::South => {self.controller_state.a.changed_button_event(value)},
                                     Button::East => {self.controller_state.b.changed_button_event(value)},
                                     Button::North => {self.controller_state.y.changed_button_event(value)},
@@ -245,6 +494,7 @@ impl GamepadManager {
                             },
                             EventType::AxisChanged(axis, value, _code) => {
                                 //println!("Axis Changed! {:?}: {value} : {_code}!", axis);
+                                let (axis, value) = self.button_remap.remap_axis(axis, value);
                                 match axis {
                                     Axis::LeftStickX => {self.controller_state.left_analog.analog_stick.changed_axis_event(value, true);}
                                     Axis::LeftStickY => {self.controller_state.left_analog.analog_stick.changed_axis_event(value, false);}
@@ -255,15 +505,20 @@ impl GamepadManager {
                             },
                             _ => (),
                         }
+                    } else if matches!(event, EventType::Connected) && self.preferred_controller.is_some() && self.preferred_controller == Some(self.controller_uuid(id)) {
+                        // The preferred controller wins over whichever one we settled for while it was unplugged
+                        println!("Preferred controller plugged in, switching to it");
+                        self.controller_state.clear_input();
+                        self.connect_to_controller(id);
                     }
                 },
                 // gilrs doesn't register new gamepads in gilrs_context.gamepads() until EventType::Connected events have been pulled off the events queue.
                 None => {
                     match event {
                         EventType::Connected => {
-                            // TODO(Samantha): Is this really what we want to do here? Reconsider when we allow changing controllers.
-                            let connected_controllers = self.get_connected_controllers();
-                            self.connect_to_controller(connected_controllers, 0)
+                            if let Some(gamepad_id) = self.choose_controller() {
+                                self.connect_to_controller(gamepad_id);
+                            }
                         },
                         _ => (),
                     }
@@ -287,11 +542,54 @@ impl GamepadManager {
         }
     }
 
-    pub fn connect_to_controller(&mut self, connected_controllers: Vec<(GamepadId, String)>, index: usize) { 
-        let gamepad_id = connected_controllers[index].0;
+    pub fn connected_controller_id(&self) -> Option<GamepadId> {self.gamepad_id}
+
+    // Hex string, since that's how it's saved in settings.toml
+    pub fn controller_uuid(&self, gamepad_id: GamepadId) -> String {
+        self.gilrs_context.gamepad(gamepad_id).uuid().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn connected_controller_uuid(&self) -> Option<String> {
+        self.gamepad_id.map(|gamepad_id| self.controller_uuid(gamepad_id))
+    }
+
+    pub fn lost_controller(&self) -> Option<String> {self.lost_controller.clone()}
+
+    // The preferred controller if it's plugged in, then the one that just dropped out, otherwise the first one gilrs knows about
+    fn choose_controller(&mut self) -> Option<GamepadId> {
+        let connected_controllers = self.get_connected_controllers();
+        let find_controller = |controller_uuid: &Option<String>| connected_controllers.iter()
+                                                                                    .find(|(gamepad_id, _)| controller_uuid.as_ref() == Some(&self.controller_uuid(*gamepad_id)))
+                                                                                    .map(|(gamepad_id, _)| *gamepad_id);
+        find_controller(&self.preferred_controller)
+            .or(find_controller(&self.lost_controller))
+            .or(connected_controllers.first().map(|(gamepad_id, _)| *gamepad_id))
+    }
+
+    // Connected events only come for newly plugged in controllers, this picks up ones that were already there
+    pub fn connect_to_available_controller(&mut self) {
+        if self.is_controller_connected() {
+            return
+        }
+        if let Some(gamepad_id) = self.choose_controller() {
+            self.connect_to_controller(gamepad_id);
+        }
+    }
+
+    pub fn connect_to_controller(&mut self, gamepad_id: GamepadId) { 
         self.gamepad_id = Some(gamepad_id);
         self.controller_type = Some(self.infer_controller_type());
-        println!("Controller connected!");
+        self.button_remap = self.controller_settings.button_remap(&self.controller_uuid(gamepad_id), self.determine_controller_type());
+        println!("Controller connected: {}", self.get_connected_controller_label());
+    }
+
+    pub fn button_remap(&self) -> ButtonRemap {self.button_remap}
+
+    // Switches to a controller picked in the remote and prefers it from now on
+    pub fn select_controller(&mut self, gamepad_id: GamepadId) {
+        self.controller_state.clear_input();
+        self.preferred_controller = Some(self.controller_uuid(gamepad_id));
+        self.connect_to_controller(gamepad_id);
     }
 
     pub fn get_connected_controller_label(&self) -> String {
@@ -313,9 +611,37 @@ impl GamepadManager {
         }
     }
 
+    // Whether the controller's buttons come from an SDL mapping, and which one, for the remote
+    pub fn get_connected_controller_mapping_source(&self) -> String {
+        let gamepad_id = match self.gamepad_id {
+            Some(gamepad_id) => gamepad_id,
+            None => return "none".to_owned(),
+        };
+        let controller_uuid = self.controller_uuid(gamepad_id);
+        let map_name = self.get_connected_controller_map_name();
+        match self.gilrs_context.gamepad(gamepad_id).mapping_source() {
+            // gilrs adds SDL_GAMECONTROLLERCONFIG last, so it wins over everything
+            MappingSource::SdlMappings if env::var(SDL_MAPPINGS_ENV_VAR).map_or(false, |mappings| mapping_names(&mappings).contains_key(&controller_uuid)) => {
+                format!("{} (from {})", map_name, SDL_MAPPINGS_ENV_VAR)
+            },
+            // The bundled mappings go in after the files, so a file's mapping is only used if the bundled ones don't replace it with their own
+            MappingSource::SdlMappings => match self.mapping_files.iter().rev().find_map(|mapping_file| mapping_file.map_names.get(&controller_uuid).map(|file_map_name| (mapping_file, file_map_name))) {
+                Some((mapping_file, file_map_name)) if !self.controller_settings.bundled_mappings() || *file_map_name == map_name => {
+                    format!("{} (from {})", map_name, mapping_file.path)
+                },
+                _ => format!("{} (bundled with gilrs)", map_name),
+            },
+            MappingSource::Driver => "none, using the driver's layout".to_owned(),
+            MappingSource::None => "none, buttons may be wrong".to_owned(),
+        }
+    }
+
     pub fn disconnect_connected_controller(&mut self) {
         if self.is_controller_connected() {
+            self.lost_controller = self.connected_controller_uuid();
             self.gamepad_id = None;
+            // Buttons held as it dropped out never get their release
+            self.controller_state.clear_input();
             println!("Controller disconnected!");
         } else {
             println!("Failed to disconnect controller. Already disconnected?");
@@ -323,16 +649,32 @@ impl GamepadManager {
     }
 
     fn infer_controller_type(&self) -> ControllerType {
+        let gamepad = self.gilrs_context.gamepad(self.gamepad_id.unwrap());
+        match (gamepad.vendor_id(), gamepad.product_id()) {
+            (Some(VALVE_VENDOR_ID), Some(STEAM_VIRTUAL_GAMEPAD_PRODUCT_ID)) => ControllerType::Xbox,
+            (Some(VALVE_VENDOR_ID), _) => ControllerType::Steam,
+            (Some(SONY_VENDOR_ID), _) => ControllerType::Playstation,
+            (Some(MICROSOFT_VENDOR_ID), _) => ControllerType::Xbox,
+            (Some(NINTENDO_VENDOR_ID), _) => ControllerType::Nintendo,
+            // Some backends (like XInput) don't report IDs, and third party pads use their own vendor IDs, so fall back on names
+            _ => self.infer_controller_type_from_name(),
+        }
+    }
+
+    fn infer_controller_type_from_name(&self) -> ControllerType {
         // Matching on both of these gives us a greater chance at automatic
-        let map_name = self.get_connected_controller_map_name().to_lowercase();
-        let label = self.get_connected_controller_label().to_lowercase();
-        
-        // TODO(Samantha): This is a candidate for lazy static, but I'm not convinced that the compiler isn't optimizing this.
-        let playstation_names = HashSet::from(["ps5 controller", "ps4 controller", "ps3 controller", "ps2 controller", "ps1 controller", "playstation", "sony"]);
-        if playstation_names.contains(map_name.as_str()) || playstation_names.contains(label.as_str()) {
+        let names = [self.get_connected_controller_map_name().to_lowercase(), self.get_connected_controller_label().to_lowercase()];
+        let name_contains = |patterns: &[&str]| names.iter().any(|name| patterns.iter().any(|pattern| name.contains(pattern)));
+        if name_contains(&["playstation", "sony", "dualshock", "dualsense", "ps5", "ps4", "ps3"]) {
             ControllerType::Playstation
-        } else {
+        } else if name_contains(&["xbox", "xinput", "x-box"]) {
             ControllerType::Xbox
+        } else if name_contains(&["nintendo", "switch", "joy-con"]) {
+            ControllerType::Nintendo
+        } else if name_contains(&["steam"]) {
+            ControllerType::Steam
+        } else {
+            ControllerType::Generic
         }
     }
 
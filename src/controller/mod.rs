@@ -1,3 +1,7 @@
 pub mod input;
 pub mod action_manager;
-mod action_handler;
\ No newline at end of file
+mod action_handler;
+pub use action_handler::release_held_input_on_panic;
+pub mod grid_navigator;
+pub mod ui_state;
+pub mod aim_ring;
\ No newline at end of file
@@ -0,0 +1,65 @@
+use crate::settings::UiStateSettings;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UiState {
+    InGame,
+    InventoryOpen,
+    PanelOpen,
+}
+
+// We can't see the game's UI, so this is a best guess built from the keys we've sent it.
+// Anything opened with a mouse click (stash, vendors) is invisible to it, hence the overrides.
+pub struct UiStateTracker {
+    settings: UiStateSettings,
+    state: UiState,
+    open_panel_key: Option<String>,
+}
+
+impl UiStateTracker {
+    pub fn new(settings: UiStateSettings) -> UiStateTracker {
+        UiStateTracker {
+            settings: settings,
+            state: UiState::InGame,
+            open_panel_key: None,
+        }
+    }
+
+    pub fn state(&self) -> UiState {self.state}
+
+    // Returns the new state if sending key_name changed it
+    pub fn key_sent(&mut self, key_name: &str) -> Option<UiState> {
+        let key_lower = key_name.to_lowercase();
+        let previous_state = self.state;
+        if self.settings.inventory_keys().contains(&key_lower) {
+            self.state = match self.state {
+                UiState::InventoryOpen => UiState::InGame,
+                _ => UiState::InventoryOpen,
+            };
+            self.open_panel_key = None;
+        } else if self.settings.panel_keys().contains(&key_lower) {
+            // Pressing a panel's key again closes it, a different panel key swaps panels
+            if self.state == UiState::PanelOpen && self.open_panel_key.as_ref() == Some(&key_lower) {
+                self.state = UiState::InGame;
+                self.open_panel_key = None;
+            } else {
+                self.state = UiState::PanelOpen;
+                self.open_panel_key = Some(key_lower);
+            }
+        } else if self.settings.close_keys().contains(&key_lower) {
+            self.state = UiState::InGame;
+            self.open_panel_key = None;
+        }
+
+        if self.state != previous_state {
+            println!("UI state changed to {:?}", self.state);
+            Some(self.state)
+        } else {
+            None
+        }
+    }
+
+    pub fn force_state(&mut self, state: UiState) {
+        self.state = state;
+        self.open_panel_key = None;
+    }
+}
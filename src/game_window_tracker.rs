@@ -1,10 +1,73 @@
-use crate::settings::ApplicationSettings;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use regex::Regex;
 
-#[derive(Clone, Copy)]
+use crate::screen_coordinates::ScreenCoordinates;
+use crate::settings::{ApplicationSettings, GameSettings, TitleMatch};
+use crate::window_finder::{self, WindowGeometry, WindowInsets};
+
+
+#[derive(Clone)]
+struct GameWindowMatcher {
+    name: String,
+    title: String,
+    title_match: TitleMatch,
+    title_regex: Option<Regex>,
+    process_name: Option<String>,
+}
+
+impl GameWindowMatcher {
+    fn new(game: GameSettings) -> GameWindowMatcher {
+        GameWindowMatcher {
+            name: game.name(),
+            // Settings already made sure this compiles
+            title_regex: match game.title_match() {
+                TitleMatch::Regex => Regex::new(&game.title()).ok(),
+                _ => None,
+            },
+            title: game.title().to_lowercase(),
+            title_match: game.title_match(),
+            process_name: game.process_name().map(|process_name| process_name.to_lowercase()),
+        }
+    }
+
+    fn matches(&self, title: &str, process_path: &Path) -> bool {
+        let title_matches = match self.title_match {
+            TitleMatch::Exact => title.to_lowercase() == self.title,
+            TitleMatch::Substring => title.to_lowercase().contains(&self.title),
+            TitleMatch::Regex => self.title_regex.as_ref().map_or(false, |title_regex| title_regex.is_match(title)),
+        };
+        // Process names match with or without their extension, "PathOfExile" or "PathOfExile.exe"
+        let process_matches = match &self.process_name {
+            Some(process_name) => {
+                let file_name = process_path.file_name().map(|name| name.to_string_lossy().to_lowercase());
+                let file_stem = process_path.file_stem().map(|stem| stem.to_string_lossy().to_lowercase());
+                file_name.as_ref() == Some(process_name) || file_stem.as_ref() == Some(process_name)
+            },
+            None => true,
+        };
+        title_matches && process_matches
+    }
+}
+
+// Asking the window manager about the active window is slow, so the tracker does it at most once per refresh interval
+// and everyone reads the cached result. The overlay and action manager share one tracker.
+#[derive(Clone)]
 pub struct GameWindowTracker {
     // TODO(chastise): This probably should be re-usable in a refactor, one for game-overlay specific things, one for remote overlay
+    games: Vec<GameWindowMatcher>,
+    refresh_interval: Duration,
+    last_refresh: Option<Instant>,
+    active_game: Option<String>,
+    game_window_center: Option<(f32, f32)>,
     windowed_mode: bool,
+    // Always-shown hints over a windowed game need its geometry while it's unfocused too
+    track_unfocused_window: bool,
+    window_insets_override: Option<WindowInsets>,
+    screen_coordinates: ScreenCoordinates,
+    // The game's client area in global desktop coordinates, which can be negative left of or above the primary monitor.
+    // In windowed mode that's inside the frame, so the cursor never lands on the title bar or borders.
     window_pos_x: f32,
     window_pos_y: f32,
     game_window_width: f32,
@@ -14,12 +77,22 @@ pub struct GameWindowTracker {
 
 impl GameWindowTracker {
     pub fn new(application_settings: ApplicationSettings) -> GameWindowTracker {
+        // Without an override, the overlay fills this in once it has found the game's monitor
+        let (screen_width, screen_height) = application_settings.overlay_settings().screen_size_override().unwrap_or((0.0, 0.0));
         GameWindowTracker { 
+            games: application_settings.games().into_iter().map(GameWindowMatcher::new).collect(),
+            refresh_interval: Duration::from_millis(application_settings.overlay_settings().window_refresh_interval_ms()),
+            last_refresh: None,
+            active_game: None,
+            game_window_center: None,
             windowed_mode: application_settings.overlay_settings().windowed_mode(),
+            track_unfocused_window: application_settings.overlay_settings().windowed_mode() && application_settings.overlay_settings().always_show_overlay(),
+            window_insets_override: application_settings.overlay_settings().window_insets(),
+            screen_coordinates: ScreenCoordinates::default(),
             window_pos_x: 0.0,
             window_pos_y: 0.0,
-            game_window_width: application_settings.overlay_settings().screen_width(),
-            game_window_height: application_settings.overlay_settings().screen_height(),
+            game_window_width: screen_width,
+            game_window_height: screen_height,
         }
     }
     pub fn windowed_mode(&self) -> bool {self.windowed_mode} // TODO: Remove this when we allow in-gui settings editing
@@ -28,40 +101,65 @@ impl GameWindowTracker {
     pub fn game_window_width(&self) -> f32 {self.game_window_width}
     pub fn game_window_height(&self) -> f32 {self.game_window_height}
 
-    pub fn is_poe_active(&self) -> bool {
-        let active_window = active_win_pos_rs::get_active_window();
-            match active_window {
-                Ok(active_window) => {
-                    active_window.title.to_lowercase() == "Path of Exile".to_lowercase()
-                },
-                Err(_) => false,
-            }
-    }
+    // Name of the configured game that has focus as of the last refresh
+    pub fn active_game(&self) -> Option<String> {self.active_game.clone()}
+    pub fn is_game_active(&self) -> bool {self.active_game.is_some()}
 
-    pub fn update_window_tracker(&mut self) {
-        (self.window_pos_x, self.window_pos_y) = self.window_position();
-        (self.game_window_width, self.game_window_height) = self.window_size();
+    // Outside windowed mode the game covers the whole screen the overlay is on
+    pub fn set_screen_rect(&mut self, screen_x: f32, screen_y: f32, screen_width: f32, screen_height: f32, scale_factor: f32) {
+        self.screen_coordinates = ScreenCoordinates::new(screen_x, screen_y, scale_factor);
+        if !self.windowed_mode {
+            (self.window_pos_x, self.window_pos_y) = (screen_x, screen_y);
+            self.game_window_width = screen_width;
+            self.game_window_height = screen_height;
+        }
     }
 
-    fn window_position(&self) -> (f32, f32) {
-        if self.is_poe_active() {
-            match active_win_pos_rs::get_position() {
-                Ok(position) => (position.x as f32, position.y as f32),
-                Err(_) => (0.0, 0.0),
+    // Converts between desktop pixels and the overlay's egui points
+    pub fn screen_coordinates(&self) -> ScreenCoordinates {self.screen_coordinates}
+
+    // Where the game window was last seen, for finding the monitor it's on
+    pub fn game_window_center(&self) -> Option<(f32, f32)> {self.game_window_center}
+
+    // Cheap to call every frame, it only queries the window manager once the refresh interval has passed
+    pub fn refresh(&mut self) {
+        if let Some(last_refresh) = self.last_refresh {
+            if last_refresh.elapsed() < self.refresh_interval {
+                return
             }
-        } else {
-            (0.0, 0.0)
         }
-    }
+        self.last_refresh = Some(Instant::now());
+
+        let active_window = active_win_pos_rs::get_active_window().ok();
+        // The first matching game wins
+        let active_game = active_window.as_ref()
+                                        .and_then(|active_window| self.games.iter().find(|game| game.matches(&active_window.title, &active_window.process_path)))
+                                        .map(|game| game.name.clone());
+        if active_game != self.active_game {
+            println!("Focused game changed to {:?}", active_game);
+        }
+        self.active_game = active_game;
 
-    fn window_size(&self) -> (f32, f32) {
-        if self.windowed_mode && self.is_poe_active() {
-            match active_win_pos_rs::get_position() {
-                Ok(position) => (position.width as f32, position.height as f32),
-                Err(_) => (0.0, 0.0),
+        let game_window = match active_window {
+            Some(active_window) if self.is_game_active() => Some(WindowGeometry {
+                x: active_window.position.x as f32,
+                y: active_window.position.y as f32,
+                width: active_window.position.width as f32,
+                height: active_window.position.height as f32,
+                insets: window_finder::foreground_window_insets(),
+            }),
+            _ if self.track_unfocused_window => window_finder::find_window(&|title, process_path| self.games.iter().any(|game| game.matches(title, process_path))),
+            _ => None,
+        };
+        // Keep the last known geometry when the game can't be found
+        if let Some(game_window) = game_window {
+            self.game_window_center = Some((game_window.x + game_window.width / 2.0, game_window.y + game_window.height / 2.0));
+            if self.windowed_mode {
+                let insets = self.window_insets_override.or(game_window.insets).unwrap_or_default();
+                (self.window_pos_x, self.window_pos_y) = (game_window.x + insets.left, game_window.y + insets.top);
+                self.game_window_width = game_window.width - insets.left - insets.right;
+                self.game_window_height = game_window.height - insets.top - insets.bottom;
             }
-        } else {
-            (self.game_window_width, self.game_window_height)
         }
     }
 }
\ No newline at end of file
@@ -14,22 +14,29 @@ use controller::{input, action_manager};
 mod overlay;
 use overlay::game_overlay;
 
+use std::{cell::RefCell, rc::Rc};
+
 use crate::game_window_tracker::GameWindowTracker;
 mod game_window_tracker;
+mod window_finder;
+mod screen_coordinates;
 
 fn main() {
     println!("Loading settings.toml...");
     let application_settings = settings::load_settings();
-    println!("Configured resolution: height:{} | width:{}", 
-                application_settings.overlay_settings().screen_height(), 
-                application_settings.overlay_settings().screen_width(),
-            );
+    match application_settings.overlay_settings().screen_size_override() {
+        Some((screen_width, screen_height)) => println!("Configured resolution: height:{} | width:{}", screen_height, screen_width),
+        None => println!("Resolution will be detected from the game's monitor"),
+    }
 
     println!("Starting gamepad manager.");
-    let gamepad_manager = input::load_gamepad_manager(application_settings.controller_settings().controller_deadzone());
+    let gamepad_manager = input::load_gamepad_manager(application_settings.controller_settings());
+    let game_window_tracker = Rc::new(RefCell::new(GameWindowTracker::new(application_settings.clone())));
+
     println!("Initializing action handler."); 
-    let game_action_handler = action_manager::ActionManager::initialize(application_settings.clone(), GameWindowTracker::new(application_settings.clone()));
+    controller::release_held_input_on_panic();
+    let game_action_handler = action_manager::ActionManager::initialize(application_settings.clone(), game_window_tracker.clone());
 
     println!("Starting overlay");
-    game_overlay::start_overlay(application_settings.overlay_settings(), application_settings.controller_settings(), gamepad_manager, game_action_handler, GameWindowTracker::new(application_settings.clone()));
+    game_overlay::start_overlay(application_settings.overlay_settings(), application_settings.controller_settings(), application_settings.input_safety_settings(), gamepad_manager, game_action_handler, game_window_tracker);
 }
\ No newline at end of file
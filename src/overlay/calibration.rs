@@ -0,0 +1,118 @@
+use crate::controller::input::ControllerState;
+use crate::settings::ControllerSettings;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CalibrationStep {
+    CharacterOffset,
+    WalkRing,
+    CloseRing,
+    MidRing,
+    FarRing,
+    Save,
+}
+
+pub enum CalibrationStatus {
+    InProgress,
+    Cancelled,
+    Finished,
+}
+
+// Walks the user through placing the crosshair on their character, then sizing each ring.
+// Changes are applied live to the controller settings it's given, so the overlay draws them as they happen.
+pub struct CalibrationWizard {
+    step: CalibrationStep,
+    original_settings: ControllerSettings,
+}
+
+impl CalibrationWizard {
+    pub fn new(controller_settings: ControllerSettings) -> CalibrationWizard {
+        CalibrationWizard {
+            step: CalibrationStep::CharacterOffset,
+            original_settings: controller_settings,
+        }
+    }
+
+    pub fn step(&self) -> CalibrationStep {self.step}
+    pub fn original_settings(&self) -> ControllerSettings {self.original_settings.clone()}
+
+    pub fn instructions(&self) -> &str {
+        match self.step {
+            CalibrationStep::CharacterOffset => "Use the d-pad to move the crosshair onto your character's center.",
+            CalibrationStep::WalkRing => "Push the right stick up or down to size the walk ring.",
+            CalibrationStep::CloseRing => "Push the right stick up or down to size the close ring.",
+            CalibrationStep::MidRing => "Push the right stick up or down to size the mid ring.",
+            CalibrationStep::FarRing => "Push the right stick up or down to size the far ring.",
+            CalibrationStep::Save => "Press A to save to settings.toml.",
+        }
+    }
+
+    // The ring being sized this step, if any
+    pub fn ring_radius_px(&self, controller_settings: &ControllerSettings, game_window_height: f32) -> Option<f32> {
+        match self.step {
+            CalibrationStep::WalkRing => Some(controller_settings.walk_circle_radius_px(game_window_height)),
+            CalibrationStep::CloseRing => Some(controller_settings.close_circle_radius_px(game_window_height)),
+            CalibrationStep::MidRing => Some(controller_settings.mid_circle_radius_px(game_window_height)),
+            CalibrationStep::FarRing => Some(controller_settings.far_circle_radius_px(game_window_height)),
+            _ => None,
+        }
+    }
+
+    // A moves on, B goes back a step, back cancels
+    pub fn handle_input(&mut self, controller_state: &mut ControllerState, controller_settings: &mut ControllerSettings, game_window_height: f32) -> CalibrationStatus {
+        let right_stick_y = controller_state.get_right_analog_stick().stick_direction()[1];
+        let buttons = controller_state.get_all_buttons();
+        let (dpad_up, dpad_down, dpad_left, dpad_right) = (buttons["dpad_up"].held, buttons["dpad_down"].held, buttons["dpad_left"].held, buttons["dpad_right"].held);
+        let (confirm, go_back, cancel) = (buttons["a"].just_pressed, buttons["b"].just_pressed, buttons["back"].just_pressed);
+        // Calibration owns the controller, nothing pressed here should reach the game afterwards
+        controller_state.clear_button_events();
+
+        if cancel {
+            return CalibrationStatus::Cancelled;
+        }
+
+        match self.step {
+            CalibrationStep::CharacterOffset => {
+                // One pixel per frame held is slow enough to tap into place and fast enough to cross the screen
+                let x_nudge = (dpad_right as i32 - dpad_left as i32) as f32;
+                let y_nudge = (dpad_up as i32 - dpad_down as i32) as f32;
+                let x_offset = controller_settings.character_x_offset_px(game_window_height) + x_nudge;
+                let y_offset = controller_settings.character_y_offset_px(game_window_height) + y_nudge;
+                controller_settings.set_character_offset_px(x_offset, y_offset, game_window_height);
+            },
+            CalibrationStep::Save => (),
+            _ => {
+                let ring_speed_px = 4.0;
+                let radius = (self.ring_radius_px(controller_settings, game_window_height).unwrap() + right_stick_y * ring_speed_px).max(0.0);
+                match self.step {
+                    CalibrationStep::WalkRing => controller_settings.set_walk_circle_radius_px(radius, game_window_height),
+                    CalibrationStep::CloseRing => controller_settings.set_close_circle_radius_px(radius, game_window_height),
+                    CalibrationStep::MidRing => controller_settings.set_mid_circle_radius_px(radius, game_window_height),
+                    CalibrationStep::FarRing => controller_settings.set_far_circle_radius_px(radius, game_window_height),
+                    _ => (),
+                }
+            },
+        }
+
+        if confirm {
+            if self.step == CalibrationStep::Save {
+                return CalibrationStatus::Finished;
+            }
+            self.step = match self.step {
+                CalibrationStep::CharacterOffset => CalibrationStep::WalkRing,
+                CalibrationStep::WalkRing => CalibrationStep::CloseRing,
+                CalibrationStep::CloseRing => CalibrationStep::MidRing,
+                CalibrationStep::MidRing => CalibrationStep::FarRing,
+                _ => CalibrationStep::Save,
+            };
+        } else if go_back {
+            self.step = match self.step {
+                CalibrationStep::Save => CalibrationStep::FarRing,
+                CalibrationStep::FarRing => CalibrationStep::MidRing,
+                CalibrationStep::MidRing => CalibrationStep::CloseRing,
+                CalibrationStep::CloseRing => CalibrationStep::WalkRing,
+                _ => CalibrationStep::CharacterOffset,
+            };
+        }
+        CalibrationStatus::InProgress
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use egui_backend::egui;
+use egui_backend::egui::{Color32, Pos2, Rect, Stroke, Vec2};
+
+use crate::controller::input::{AnalogStick, ControllerState};
+
+const BUTTON_ORDER: [&str; 16] = [
+    "a", "b", "x", "y",
+    "dpad_up", "dpad_down", "dpad_left", "dpad_right",
+    "bumper_left", "bumper_right", "trigger_left", "trigger_right",
+    "left_analog", "right_analog", "start", "back",
+];
+const STICK_PLOT_SIZE: f32 = 100.0;
+const HELD_COLOR: Color32 = Color32::from_rgb(227, 117, 0);
+
+// Shows the live ControllerState after remapping, so users can tell a worn out stick or a bad mapping
+// from a settings problem. Presses are counted rather than flashed, since just_pressed only lasts a frame.
+#[derive(Default)]
+pub struct ControllerTester {
+    press_counts: HashMap<String, u32>,
+}
+
+impl ControllerTester {
+    // Nothing else reads controller state while the remote is open, so this clears the button events it counts
+    pub fn show(&mut self, ui: &mut egui::Ui, controller_state: &mut ControllerState) {
+        let buttons = controller_state.get_all_buttons();
+        egui::Grid::new("Controller Tester Buttons").num_columns(4).show(ui, |ui| {
+            for (index, button_name) in BUTTON_ORDER.iter().enumerate() {
+                let button = &buttons[*button_name];
+                let press_count = self.press_counts.entry(button_name.to_string()).or_insert(0);
+                if button.just_pressed {
+                    *press_count += 1;
+                }
+                let color = if button.held {HELD_COLOR} else {Color32::GRAY};
+                ui.colored_label(color, format!("{} ({})", button_name, press_count))
+                    .on_hover_text("Held buttons are highlighted, the number counts presses");
+                if index % 4 == 3 {
+                    ui.end_row();
+                }
+            }
+        });
+        controller_state.clear_button_events();
+
+        ui.horizontal(|ui| {
+            draw_stick_plot(ui, "Left stick", controller_state.get_left_analog_stick());
+            draw_stick_plot(ui, "Right stick", controller_state.get_right_analog_stick());
+        });
+
+        let (left_trigger, left_threshold) = controller_state.get_left_trigger();
+        draw_trigger_bar(ui, "Left trigger", left_trigger, left_threshold);
+        let (right_trigger, right_threshold) = controller_state.get_right_trigger();
+        draw_trigger_bar(ui, "Right trigger", right_trigger, right_threshold);
+        if ui.button("Reset press counts").clicked() {
+            self.press_counts.clear();
+        }
+    }
+}
+
+// Gray is what the controller reports, orange is what's left after the deadzone
+fn draw_stick_plot(ui: &mut egui::Ui, label: &str, analog_stick: AnalogStick) {
+    ui.vertical(|ui| {
+        let raw_direction = analog_stick.raw_stick_direction();
+        let direction = analog_stick.stick_direction();
+        ui.label(label);
+        let (response, painter) = ui.allocate_painter(Vec2::splat(STICK_PLOT_SIZE), egui::Sense::hover());
+        let center = response.rect.center();
+        let radius = STICK_PLOT_SIZE / 2.0 - 2.0;
+        // Stick up is positive, screen up is negative
+        let to_plot = |stick_x: f32, stick_y: f32| Pos2 { x: center.x + stick_x * radius, y: center.y - stick_y * radius };
+        painter.circle_stroke(center, radius, Stroke { width: 1.0, color: Color32::GRAY });
+        // The deadzone applies to each axis on its own, so the stick only reads as centered inside a square
+        let deadzone = analog_stick.deadzone() * radius;
+        painter.rect_stroke(Rect::from_center_size(center, Vec2::splat(deadzone * 2.0)), 0.0, Stroke { width: 1.0, color: Color32::from_rgb(200, 60, 60) });
+        painter.line_segment([to_plot(-1.0, 0.0), to_plot(1.0, 0.0)], Stroke { width: 0.5, color: Color32::DARK_GRAY });
+        painter.line_segment([to_plot(0.0, -1.0), to_plot(0.0, 1.0)], Stroke { width: 0.5, color: Color32::DARK_GRAY });
+        painter.circle_filled(to_plot(raw_direction[0], raw_direction[1]), 4.0, Color32::LIGHT_GRAY);
+        painter.circle_filled(to_plot(direction[0], direction[1]), 3.0, HELD_COLOR);
+        ui.label(format!("raw {:.2}, {:.2}", raw_direction[0], raw_direction[1]));
+        ui.label(format!("out {:.2}, {:.2}", direction[0], direction[1]));
+    });
+}
+
+fn draw_trigger_bar(ui: &mut egui::Ui, label: &str, value: f32, threshold: f32) {
+    ui.horizontal(|ui| {
+        ui.label(format!("{}: {:.2} / {:.2}", label, value, threshold));
+        let (response, painter) = ui.allocate_painter(Vec2::new(120.0, 12.0), egui::Sense::hover());
+        let bar = response.rect;
+        let color = if value >= threshold {HELD_COLOR} else {Color32::GRAY};
+        painter.rect_filled(Rect::from_min_size(bar.min, Vec2::new(bar.width() * value.clamp(0.0, 1.0), bar.height())), 0.0, color);
+        painter.rect_stroke(bar, 0.0, Stroke { width: 1.0, color: Color32::GRAY });
+        let threshold_x = bar.min.x + bar.width() * threshold;
+        painter.line_segment([Pos2 { x: threshold_x, y: bar.min.y }, Pos2 { x: threshold_x, y: bar.max.y }], Stroke { width: 2.0, color: Color32::WHITE });
+    });
+}
@@ -26,13 +26,16 @@ fn load_pixel_icon() -> PixelImage {
     PixelImage {width: img_width, height: img_height, pixels: img_pixels}
 }
 
-pub fn start_egui_overlay(app: impl UserApp<egui_window_glfw_passthrough::GlfwWindow, egui_render_wgpu::WgpuBackend> + 'static, screen_width: i32, screen_height: i32) {
+pub fn start_egui_overlay(app: impl UserApp<egui_window_glfw_passthrough::GlfwWindow, egui_render_wgpu::WgpuBackend> + 'static, screen_size: Option<(i32, i32)>) {
     let mut glfw_backend =
         egui_window_glfw_passthrough::GlfwWindow::new(Default::default(), Default::default());
     let wgpu_backend = egui_render_wgpu::WgpuBackend::new(&mut glfw_backend, Default::default());
 
     glfw_backend.window.set_icon_from_pixels(vec![load_pixel_icon()]);
     glfw_backend.window.set_title("Exile Controller");
-    glfw_backend.window.set_size(screen_width, screen_height);
+    // Without a configured size, the overlay sizes itself to the game's monitor on its first frame
+    if let Some((screen_width, screen_height)) = screen_size {
+        glfw_backend.window.set_size(screen_width, screen_height);
+    }
     glfw_backend.run_event_loop(wgpu_backend, app);
 }
\ No newline at end of file
@@ -1,10 +1,15 @@
 use std::process::exit;
+use std::{cell::RefCell, rc::Rc};
+use std::time::{Duration, Instant};
 
 use super::egui_overlay;
-use crate::controller::action_manager::ActionManager;
-use crate::controller::input::{GamepadManager, ControllerType};
+use crate::controller::action_manager::{ActionManager, ControlMode};
+use crate::controller::input::{GamepadManager, ControllerType, ReconnectPolicy};
+use crate::controller::aim_ring::AimRing;
 use crate::game_window_tracker::GameWindowTracker;
-use crate::settings::{OverlaySettings, ControllerSettings};
+use crate::settings::{self, OverlaySettings, ControllerSettings, InputSafetySettings};
+use super::calibration::{CalibrationWizard, CalibrationStatus};
+use super::controller_tester::ControllerTester;
 
 use egui::{Vec2, Context, epaint, Color32};
 use egui_backend::{egui, UserApp};
@@ -27,10 +32,11 @@ impl ControllerImage {
             xbox: RetainedImage::from_image_bytes(debug_name, &fs::read(xbox).unwrap()).unwrap(),
         }
     }
+    // There are only PlayStation and Xbox glyphs, every other family is shown the Xbox ones
     fn choose_image(&self, controller_type: ControllerType) -> &RetainedImage {
         match controller_type {
             ControllerType::Playstation => &self.playstation,
-            ControllerType::Xbox => &self.xbox,
+            ControllerType::Xbox | ControllerType::Nintendo | ControllerType::Steam | ControllerType::Generic => &self.xbox,
         }
     }
 }
@@ -48,16 +54,41 @@ struct OverlayImages {
 
     button_bumper_left: ControllerImage,
     button_bumper_right: ControllerImage,
-    // button_trigger_left: ControllerImage,
-    // button_trigger_right: ControllerImage,
+    button_trigger_left: ControllerImage,
+    button_trigger_right: ControllerImage,
 
     left_stick: ControllerImage,
-    // right_stick: ControllerImage,
+    right_stick: ControllerImage,
 
-    // button_l3: ControllerImage,
+    button_l3: ControllerImage,
     button_r3: ControllerImage,
 }
 
+impl OverlayImages {
+    // Takes ControllerState's button names, plus "left_stick" and "right_stick"
+    fn button_image(&self, button_name: &str) -> &ControllerImage {
+        match button_name {
+            "dpad_up" => &self.button_d_up,
+            "dpad_down" => &self.button_d_down,
+            "dpad_left" => &self.button_d_left,
+            "dpad_right" => &self.button_d_right,
+            "a" => &self.button_face_down,
+            "b" => &self.button_face_right,
+            "x" => &self.button_face_left,
+            "y" => &self.button_face_up,
+            "bumper_left" => &self.button_bumper_left,
+            "bumper_right" => &self.button_bumper_right,
+            "trigger_left" => &self.button_trigger_left,
+            "trigger_right" => &self.button_trigger_right,
+            "left_stick" => &self.left_stick,
+            "right_stick" => &self.right_stick,
+            "left_analog" => &self.button_l3,
+            "right_analog" => &self.button_r3,
+            _ => panic!("No overlay image for {}", button_name),
+        }
+    }
+}
+
 
 impl Default for OverlayImages {
     fn default() -> Self {
@@ -74,13 +105,13 @@ impl Default for OverlayImages {
 
             button_bumper_left: ControllerImage::new("lb.png", "img/playstation/ps_lb.png",  "img/xbox/xb_lb.png"),
             button_bumper_right: ControllerImage::new("rb.png", "img/playstation/ps_rb.png", "img/xbox/xb_rb.png"),
-            // button_trigger_left: ControllerImage::new("lt.png", "img/playstation/ps_lt.png", "img/xbox/xb_lt.png"),
-            // button_trigger_right: ControllerImage::new("rb.png", "img/playstation/ps_rb.png", "img/xbox/xb_rb.png"),
+            button_trigger_left: ControllerImage::new("lt.png", "img/playstation/ps_lt.png", "img/xbox/xb_lt.png"),
+            button_trigger_right: ControllerImage::new("rt.png", "img/playstation/ps_rt.png", "img/xbox/xb_rt.png"),
 
             left_stick: ControllerImage::new("left_analog.png", "img/playstation/left_analog.png", "img/xbox/left_analog.png"),
-            // right_stick: ControllerImage::new("right_analog.png", "img/playstation/right_analog.png", "img/xbox/right_analog.png"),
+            right_stick: ControllerImage::new("right_analog.png", "img/playstation/right_analog.png", "img/xbox/right_analog.png"),
 
-            // button_l3: ControllerImage::new("button_l3.png", "img/playstation/button_l3.png", "img/xbox/button_l3.png"),
+            button_l3: ControllerImage::new("button_l3.png", "img/playstation/button_l3.png", "img/xbox/button_l3.png"),
             button_r3: ControllerImage::new("button_r3.png", "img/playstation/button_r3.png", "img/xbox/button_r3.png"),
         }
     }
@@ -90,22 +121,49 @@ impl Default for OverlayImages {
 
 struct GameOverlay {
     overlay_settings: OverlaySettings,
-    game_window_tracker: GameWindowTracker,
-    window_rect: Rect, // TODO: Can get rid of this
+    game_window_tracker: Rc<RefCell<GameWindowTracker>>,
+    window_rect: Rect, // The screen we cover, detected from the game's monitor unless overridden in settings
     overlay_images: OverlayImages,
     controller_settings: ControllerSettings,
+    input_safety_settings: InputSafetySettings,
     gamepad_manager: GamepadManager, 
     game_action_handler: ActionManager,
     remote_open: bool,
     remote_pos: Pos2,
     game_input_started: bool,
+    calibration_wizard: Option<CalibrationWizard>,
+    game_unfocused_since: Option<Instant>,
+    input_suspended: bool,
+    emergency_chord_since: Option<Instant>,
+    awaiting_reconnect: bool,
+    controller_tester: ControllerTester,
 }
 
 impl GameOverlay {
+    // Tracker and cursor positions are global desktop pixels, egui's are points relative to the overlay window
+    fn to_overlay_pos(&self, desktop_pos: Pos2) -> Pos2 {
+        let (overlay_x, overlay_y) = self.game_window_tracker.borrow().screen_coordinates().to_overlay(desktop_pos.x, desktop_pos.y);
+        Pos2 { x: overlay_x, y: overlay_y }
+    }
+
+    fn to_overlay_length(&self, desktop_length: f32) -> f32 {
+        self.game_window_tracker.borrow().screen_coordinates().to_overlay_length(desktop_length)
+    }
+
+    fn overlay_rect(&self) -> Rect {
+        overlay_rect(self.window_rect, self.game_window_tracker.borrow().screen_coordinates().scale_factor())
+    }
+
+    // The glyph of whichever physical button the controller's remap turns into this one
+    fn button_glyph<'a>(&self, images: &'a OverlayImages, button_name: &str) -> &'a RetainedImage {
+        let physical_button = self.gamepad_manager.button_remap().physical_button(button_name);
+        images.button_image(&physical_button).choose_image(self.gamepad_manager.determine_controller_type())
+    }
+
     fn place_overlay_image(&self, ctx: &Context, image: &RetainedImage, position: Pos2, id_source: &str) {
         egui_backend::egui::Area::new(id_source)
                                     .movable(false)
-                                    .fixed_pos(position)
+                                    .fixed_pos(self.to_overlay_pos(position))
                                     .interactable(false)
                                     .show(ctx,|ui| {
                                         ui.image(image.texture_id(ctx), image.size_vec2());
@@ -113,82 +171,89 @@ impl GameOverlay {
     }
 
     fn place_face_overlay_images (&self, ctx: &Context, images: &OverlayImages) {
+        let game_window_tracker = self.game_window_tracker.borrow();
         let x_offset = 0.828;
         let x_offset_offset = 0.029;
         let y_offset = 0.97;
 
-        let controller_type = self.gamepad_manager.determine_controller_type();
-        self.place_overlay_image(ctx, &images.button_face_left.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*3.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "x"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*3.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                        "button_face_left");
-        self.place_overlay_image(ctx, &images.button_face_down.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "a"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_face_down");
-        self.place_overlay_image(ctx, &images.button_face_right.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "b"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_face_right");
-        self.place_overlay_image(ctx, &images.button_face_up.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "y"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_face_up");
     }
 
     fn place_flask_overlay_images (&self, ctx: &Context, images: &OverlayImages) {
+        let game_window_tracker = self.game_window_tracker.borrow();
         let x_offset = 0.2615;
         let x_offset_offset = 0.0242;
         let y_offset = 0.97;
 
-        let controller_type = self.gamepad_manager.determine_controller_type();
-        self.place_overlay_image(ctx, &images.button_d_left.choose_image(controller_type),
-            Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*4.0) + self.game_window_tracker.window_pos_x(), 
-                y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "dpad_left"),
+            Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*4.0) + game_window_tracker.window_pos_x(), 
+                y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
            "button_d_left");
-        self.place_overlay_image(ctx, &images.button_d_down.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*3.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "dpad_down"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*3.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                        "button_d_down");
-        self.place_overlay_image(ctx, &images.button_d_right.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "dpad_right"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_d_right");
-        self.place_overlay_image(ctx, &images.button_d_up.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "dpad_up"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_d_up");
-        self.place_overlay_image(ctx, &images.button_r3.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "right_analog"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_r3");
     }
 
     fn place_mouse_button_overlay_images (&self, ctx: &Context, images: &OverlayImages) {
+        let game_window_tracker = self.game_window_tracker.borrow();
         let x_offset = 0.8585;
         let x_offset_offset = 0.029;
         let y_offset = 0.909;
 
-        let controller_type = self.gamepad_manager.determine_controller_type();
-        self.place_overlay_image(ctx, &images.left_stick.choose_image(controller_type),
-            Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + self.game_window_tracker.window_pos_x(), 
-                y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "left_stick"),
+            Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*2.0) + game_window_tracker.window_pos_x(), 
+                y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
             "left_stick");
-        self.place_overlay_image(ctx, &images.button_bumper_left.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "bumper_left"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset-x_offset_offset*1.0) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                        "button_bumper_left");
-        self.place_overlay_image(ctx, &images.button_bumper_right.choose_image(controller_type),
-                        Pos2 { x: self.game_window_tracker.game_window_width() * (x_offset) + self.game_window_tracker.window_pos_x(), 
-                            y: self.game_window_tracker.game_window_height() * y_offset + self.game_window_tracker.window_pos_y() },
+        self.place_overlay_image(ctx, self.button_glyph(images, "bumper_right"),
+                        Pos2 { x: game_window_tracker.game_window_width() * (x_offset) + game_window_tracker.window_pos_x(), 
+                            y: game_window_tracker.game_window_height() * y_offset + game_window_tracker.window_pos_y() },
                         "button_bumper_right");
     }
 
+    // Matches where ActionManager centers its rings
+    fn character_position (&self) -> Pos2 {
+        let game_window_tracker = self.game_window_tracker.borrow();
+        let game_window_height = game_window_tracker.game_window_height();
+        Pos2 { x: (game_window_tracker.game_window_width() / 2.0) + self.controller_settings.character_x_offset_px(game_window_height) + game_window_tracker.window_pos_x(), 
+               y: (game_window_height / 2.0) - self.controller_settings.character_y_offset_px(game_window_height) + game_window_tracker.window_pos_y()}
+    }
+
     fn paint_crosshair (&self, ctx: &Context) {
         let crosshair_radius = 5.0;
         // offset radius*2.0 because the paint area is radius * 4 across
-        let crosshair_position = Pos2 { x: (self.game_window_tracker.game_window_width() / 2.0) - self.controller_settings.character_x_offset_px() - crosshair_radius*2.0  + self.game_window_tracker.window_pos_x(), 
-                                                y: (self.game_window_tracker.game_window_height() / 2.0) - self.controller_settings.character_y_offset_px() - crosshair_radius*2.0 + self.game_window_tracker.window_pos_y()};
+        let crosshair_position = self.to_overlay_pos(self.character_position()) - Vec2::splat(crosshair_radius*2.0);
         egui::Area::new("crosshair")
                         .movable(false)
                         .fixed_pos(crosshair_position)
@@ -200,6 +265,130 @@ impl GameOverlay {
                         });
     }
 
+    fn paint_aim_rings (&self, ctx: &Context) {
+        let center = self.to_overlay_pos(self.character_position());
+        let game_window_height = self.game_window_tracker.borrow().game_window_height();
+        let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Background, egui::Id::new("aim_rings")));
+        let rings = [
+            (self.controller_settings.walk_circle_radius_px(game_window_height), Color32::from_rgba_unmultiplied(255, 255, 255, 90)),
+            (self.controller_settings.close_circle_radius_px(game_window_height), Color32::from_rgba_unmultiplied(90, 200, 90, 120)),
+            (self.controller_settings.mid_circle_radius_px(game_window_height), Color32::from_rgba_unmultiplied(227, 177, 0, 120)),
+            (self.controller_settings.far_circle_radius_px(game_window_height), Color32::from_rgba_unmultiplied(227, 60, 0, 120)),
+        ];
+        for (radius, color) in rings {
+            self.paint_aim_ring(&painter, center, radius, color);
+        }
+    }
+
+    // Center is in overlay points, radius in desktop pixels like the settings
+    fn paint_aim_ring (&self, painter: &egui::Painter, center: Pos2, radius: f32, color: Color32) {
+        let outline: Vec<Pos2> = AimRing::new(radius, &self.controller_settings).outline(64)
+                                            .into_iter()
+                                            .map(|(offset_x, offset_y)| Pos2 { x: center.x + self.to_overlay_length(offset_x), y: center.y - self.to_overlay_length(offset_y) })
+                                            .collect();
+        painter.add(epaint::Shape::closed_line(outline, egui::Stroke{width: 1.5, color: color}));
+    }
+
+    // Shows what ActionManager is working with: its rings, the stick angles it aims along,
+    // where it last put the cursor and what ActionHandler is holding down
+    fn paint_debug_overlay (&self, ctx: &Context) {
+        self.paint_aim_rings(ctx);
+        let center = self.to_overlay_pos(self.character_position());
+        let game_window_height = self.game_window_tracker.borrow().game_window_height();
+        let far_ring = AimRing::new(self.controller_settings.far_circle_radius_px(game_window_height), &self.controller_settings);
+        let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Background, egui::Id::new("debug_overlay")));
+        let rays = [
+            (self.game_action_handler.holding_walk(), self.game_action_handler.walking_angle(), Color32::from_rgb(90, 200, 90)),
+            (self.game_action_handler.holding_aim(), self.game_action_handler.aiming_angle(), Color32::from_rgb(227, 117, 0)),
+        ];
+        for (held, angle, color) in rays {
+            // The angle is kept after the stick is let go, so fade it out rather than hiding it
+            let color = if held {color} else {color.linear_multiply(0.3)};
+            let (offset_x, offset_y) = far_ring.offset_at(angle);
+            let ray_end = Pos2 { x: center.x + self.to_overlay_length(offset_x), y: center.y - self.to_overlay_length(offset_y) };
+            painter.line_segment([center, ray_end], egui::Stroke{width: 2.0, color: color});
+        }
+
+        if let Some((cursor_x, cursor_y)) = self.game_action_handler.last_cursor_target() {
+            let cursor_position = self.to_overlay_pos(Pos2 { x: cursor_x as f32, y: cursor_y as f32 });
+            let cursor_color = Color32::from_rgb(0, 200, 255);
+            painter.circle_stroke(cursor_position, 6.0, egui::Stroke{width: 2.0, color: cursor_color});
+            painter.line_segment([cursor_position - Vec2::new(9.0, 0.0), cursor_position + Vec2::new(9.0, 0.0)], egui::Stroke{width: 1.0, color: cursor_color});
+            painter.line_segment([cursor_position - Vec2::new(0.0, 9.0), cursor_position + Vec2::new(0.0, 9.0)], egui::Stroke{width: 1.0, color: cursor_color});
+        }
+
+        let game_window_tracker = self.game_window_tracker.borrow();
+        let label_position = Pos2 { x: game_window_tracker.game_window_width() * 0.01 + game_window_tracker.window_pos_x(), 
+                                    y: game_window_tracker.game_window_height() * 0.02 + game_window_tracker.window_pos_y() };
+        let held_actions = self.game_action_handler.held_actions();
+        egui::Area::new("debug_held_actions")
+                        .movable(false)
+                        .fixed_pos(self.to_overlay_pos(label_position))
+                        .interactable(false)
+                        .show(ctx,|ui| {
+                            let held_text = if held_actions.is_empty() {"nothing".to_owned()} else {held_actions.join(", ")};
+                            ui.label(egui::RichText::new(format!("Holding: {}", held_text))
+                                                    .color(Color32::WHITE)
+                                                    .background_color(Color32::from_black_alpha(180))
+                                                    .monospace());
+                        });
+    }
+
+    fn paint_control_mode (&self, ctx: &Context) {
+        let game_window_tracker = self.game_window_tracker.borrow();
+        if self.game_action_handler.control_mode() != ControlMode::Cursor {
+            return
+        }
+        let label_position = Pos2 { x: game_window_tracker.game_window_width() * 0.47 + game_window_tracker.window_pos_x(), 
+                                    y: game_window_tracker.game_window_height() * 0.02 + game_window_tracker.window_pos_y() };
+        egui::Area::new("control_mode")
+                        .movable(false)
+                        .fixed_pos(self.to_overlay_pos(label_position))
+                        .interactable(false)
+                        .show(ctx,|ui| {
+                            ui.label(egui::RichText::new("Cursor Mode")
+                                                    .color(Color32::from_rgb(227, 117, 0))
+                                                    .background_color(Color32::from_black_alpha(180))
+                                                    .size(16.0)
+                                                    .strong());
+                        });
+    }
+
+    fn handle_calibration (&mut self, ctx: &Context) {
+        let game_window_height = self.game_window_tracker.borrow().game_window_height();
+        let calibration_status = match &mut self.calibration_wizard {
+            Some(calibration_wizard) => calibration_wizard.handle_input(&mut self.gamepad_manager.controller_state, &mut self.controller_settings, game_window_height),
+            None => return,
+        };
+        match calibration_status {
+            CalibrationStatus::InProgress => {
+                self.paint_crosshair(ctx);
+                let ring_radius = self.calibration_wizard.as_ref().unwrap().ring_radius_px(&self.controller_settings, game_window_height);
+                if let Some(radius) = ring_radius {
+                    let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Background, egui::Id::new("calibration_ring")));
+                    self.paint_aim_ring(&painter, self.to_overlay_pos(self.character_position()), radius, Color32::from_rgb(227, 117, 0));
+                }
+            },
+            CalibrationStatus::Cancelled => self.stop_calibration(false),
+            CalibrationStatus::Finished => self.stop_calibration(true),
+        }
+    }
+
+    fn stop_calibration (&mut self, save: bool) {
+        if let Some(calibration_wizard) = self.calibration_wizard.take() {
+            if save {
+                match settings::save_calibrated_controller_settings(&self.controller_settings) {
+                    Ok(()) => println!("Saved calibration to settings.toml"),
+                    Err(error) => println!("Unable to save calibration to settings.toml: {}", error),
+                }
+                self.game_action_handler.set_controller_settings(self.controller_settings.clone());
+            } else {
+                println!("Calibration cancelled");
+                self.controller_settings = calibration_wizard.original_settings();
+            }
+        }
+    }
+
     fn draw_remote(&mut self, ctx: &Context) {
         let new_pos;
         let mut gui_style = (*ctx.style()).clone();
@@ -233,57 +422,88 @@ impl GameOverlay {
             new_pos = egui::Window::new(egui::RichText::new("Exile Controller").color(Color32::from_rgb(227, 117, 0)).strong())
                                     .resizable(false)
                                     .current_pos(self.remote_pos)
-                                    .drag_bounds(self.window_rect)
+                                    .drag_bounds(self.overlay_rect())
                                     .collapsible(false)
                                     .show(ctx,|ui| {
                                         egui::Grid::new("Remote Grid ID").min_col_width(220.0).show(ui, |ui| {
                                             let mut can_overlay_start = true;
-                                            if self.gamepad_manager.is_controller_connected() {
+                                            if self.gamepad_manager.is_controller_connected() && self.calibration_wizard.is_none() {
+                                                self.draw_controller_picker(ui);
+                                            } else if self.gamepad_manager.is_controller_connected() {
                                                 let controller_label =  self.gamepad_manager.get_connected_controller_label();
                                                 ui.label(String::from("Controller connected: ") + controller_label.as_str());
-                                            //     let mut selected = 0 as usize;
-                                            //     egui::ComboBox::from_label("Select Connected Controller:")
-                                            //     .selected_text(format!("{:?}", selected))
-                                            //     .show_index(ui, &mut selected, connected_controllers.len(), |i| connected_controllers[i].1.to_owned());
-                                            //     self.gamepad_manager.select_connected_controller(connected_controllers.get(selected).unwrap().0);
-                                            // } else {
-                                            //     let mut selected = 0 as usize;
-                                            //     egui::ComboBox::from_label("Connect a controller").selected_text("None connected").show_index(ui, &mut selected, 1, |_i| "".to_string());
                                             } else {
                                                 ui.label(String::from("No controller connected."));
                                                 can_overlay_start = false;
                                             }
                                             ui.end_row();
+                                            if self.gamepad_manager.is_controller_connected() {
+                                                ui.label(String::from("Mapping: ") + self.gamepad_manager.get_connected_controller_mapping_source().as_str())
+                                                    .on_hover_text("Add a gamecontrollerdb.txt to mapping_files in settings.toml if the buttons are wrong");
+                                                ui.end_row();
+                                            }
+                                            match self.game_window_tracker.borrow().active_game() {
+                                                Some(active_game) => ui.label(String::from("Game focused: ") + active_game.as_str()),
+                                                None => ui.label(String::from("No configured game focused.")),
+                                            };
+                                            ui.end_row();
+                                            if let Some(calibration_wizard) = &self.calibration_wizard {
+                                                ui.label(format!("Calibrating {:?}: {}", calibration_wizard.step(), calibration_wizard.instructions()));
+                                                ui.end_row();
+                                            }
                                             ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
-                                                let start_button = ui.add_enabled(can_overlay_start, egui::Button::new("Start Controller Input"));
-                                                if start_button.clicked() {
-                                                    self.remote_open = false;
-                                                    self.game_input_started = true;
+                                                if self.calibration_wizard.is_some() {
+                                                    let cancel_button = ui.add_enabled(true, egui::Button::new("Cancel Calibration"));
+                                                    if cancel_button.clicked() {
+                                                        self.stop_calibration(false);
+                                                    }
+                                                } else {
+                                                    let start_button = ui.add_enabled(can_overlay_start, egui::Button::new("Start Controller Input"));
+                                                    if start_button.clicked() {
+                                                        self.remote_open = false;
+                                                        self.game_input_started = true;
+                                                    }
+                                                    let calibrate_button = ui.add_enabled(can_overlay_start, egui::Button::new("Calibrate"))
+                                                                                            .on_hover_text("Line up the crosshair and rings with your character");
+                                                    if calibrate_button.clicked() {
+                                                        self.calibration_wizard = Some(CalibrationWizard::new(self.controller_settings.clone()));
+                                                    }
                                                 }
                                                 let quit_button = ui.add_enabled(true, egui::Button::new("Quit"));
                                                 if quit_button.clicked() {
+                                                    // exit() skips destructors, let go of everything first
+                                                    self.game_action_handler.release_all();
                                                     exit(0);
                                                 }
                                             });
                                         });
+                                        // Calibration reads the same button presses
+                                        if self.gamepad_manager.is_controller_connected() && self.calibration_wizard.is_none() {
+                                            ui.collapsing("Controller Tester", |ui| {
+                                                self.controller_tester.show(ui, &mut self.gamepad_manager.controller_state);
+                                            });
+                                        }
                                     }).unwrap().response.rect.left_top();
         } else {
             // Draw the minimized remote
             new_pos =  egui::Window::new("Exile Controller Minimized Remote")
                                     .resizable(false)
                                     .current_pos(self.remote_pos)
-                                    .drag_bounds(self.window_rect)
+                                    .drag_bounds(self.overlay_rect())
                                     .title_bar(false)
                                     .show(ctx,|ui| {
                                         egui::Grid::new("Pause Grid ID").min_col_width(220.0).show(ui, |ui| {
+                                            if self.awaiting_reconnect {
+                                                ui.label("Controller disconnected, waiting for it to reconnect");
+                                                ui.end_row();
+                                            }
                                             ui.with_layout(egui::Layout::centered_and_justified(egui::Direction::TopDown), |ui| {
                                                 let pause_button = ui.button(egui::RichText::new("Pause Overlay")
                                                                                             .color(Color32::from_rgb(227, 117, 0))
                                                                                             .size(14.0)
                                                                                         ).on_hover_text("Pause Controller Input");
                                                 if pause_button.clicked() {
-                                                    self.remote_open = true;
-                                                    self.game_input_started = false;
+                                                    self.pause_game_input();
                                                 }
                                             })
                                         })
@@ -292,6 +512,69 @@ impl GameOverlay {
         self.update_remote_pos(new_pos);
     }
 
+    // Lists every connected gamepad, so a wheel or second pad can't take over from the one being played with
+    fn draw_controller_picker(&mut self, ui: &mut egui::Ui) {
+        let connected_controllers = self.gamepad_manager.get_connected_controllers();
+        let current_controller = self.gamepad_manager.connected_controller_id();
+        let mut selected_controller = current_controller;
+        egui::ComboBox::from_label("Controller")
+                        .selected_text(self.gamepad_manager.get_connected_controller_label())
+                        .width(200.0)
+                        .show_ui(ui, |ui| {
+                            for (gamepad_id, controller_name) in &connected_controllers {
+                                // Numbered, since two of the same pad have the same name
+                                ui.selectable_value(&mut selected_controller, Some(*gamepad_id), format!("{} ({})", controller_name, gamepad_id));
+                            }
+                        });
+        if let Some(gamepad_id) = selected_controller.filter(|_| selected_controller != current_controller) {
+            self.game_action_handler.release_all();
+            self.gamepad_manager.select_controller(gamepad_id);
+            let controller_uuid = self.gamepad_manager.controller_uuid(gamepad_id);
+            self.controller_settings.set_preferred_controller(controller_uuid.clone());
+            match settings::save_preferred_controller(&controller_uuid) {
+                Ok(()) => println!("Saved preferred controller to settings.toml"),
+                Err(error) => println!("Unable to save preferred controller to settings.toml: {}", error),
+            }
+        }
+    }
+
+    fn pause_game_input(&mut self) {
+        self.remote_open = true;
+        self.game_input_started = false;
+        self.emergency_chord_since = None;
+        self.awaiting_reconnect = false;
+        self.game_action_handler.release_all();
+        self.gamepad_manager.controller_state.clear_button_events();
+    }
+
+    // Keeps the overlay covering whichever monitor the game is on, in global desktop coordinates
+    fn update_screen_rect(&mut self, glfw_backend: &mut egui_window_glfw_passthrough::GlfwWindow) {
+        let game_window_center = self.game_window_tracker.borrow().game_window_center();
+        let game_monitor = detect_game_monitor(glfw_backend, game_window_center);
+        let (screen_rect, scale_factor) = match (self.overlay_settings.screen_size_override(), game_monitor) {
+            (Some((screen_width, screen_height)), game_monitor) => (Rect::from_min_size(game_monitor.map_or(Pos2::ZERO, |(monitor_rect, _)| monitor_rect.min), Vec2 { x: screen_width, y: screen_height }),
+                                                                     game_monitor.map_or(1.0, |(_, scale_factor)| scale_factor)),
+            (None, Some(game_monitor)) => game_monitor,
+            (None, None) if self.window_rect.is_positive() => return,
+            // No monitor to go by yet, so cover the window as it was created rather than sizing it from nothing
+            (None, None) => {
+                let ((window_x, window_y), (window_width, window_height)) = (glfw_backend.window.get_pos(), glfw_backend.window.get_size());
+                (Rect::from_min_size(Pos2 { x: window_x as f32, y: window_y as f32 }, Vec2 { x: window_width as f32, y: window_height as f32 }), 1.0)
+            },
+        };
+        if screen_rect == self.window_rect && scale_factor == self.game_window_tracker.borrow().screen_coordinates().scale_factor() {
+            return
+        }
+        println!("Covering screen at x:{} y:{} | height:{} | width:{} | scale:{}", screen_rect.min.x, screen_rect.min.y, screen_rect.height(), screen_rect.width(), scale_factor);
+        self.game_window_tracker.borrow_mut().set_screen_rect(screen_rect.min.x, screen_rect.min.y, screen_rect.width(), screen_rect.height(), scale_factor);
+        // The remote starts out placed on the first screen we find, and follows if it's left off-screen
+        let overlay_rect = overlay_rect(screen_rect, scale_factor);
+        if !self.window_rect.is_positive() || !overlay_rect.contains(self.remote_pos) {
+            self.remote_pos = default_remote_pos(overlay_rect);
+        }
+        self.window_rect = screen_rect;
+    }
+
     fn update_remote_pos(&mut self, new_position: Pos2) {
         self.remote_pos = new_position;
     }
@@ -302,10 +585,82 @@ impl GameOverlay {
 
     // }
 
-    fn handle_controller_input_loop (&mut self, ctx: &Context) {
-        if self.overlay_settings.windowed_mode() && self.game_window_tracker.is_poe_active() {
-            self.game_action_handler.update_window_tracker();
+    // Runs while controller input is started and the controller has dropped out, until it's back or we give up and pause
+    fn wait_for_reconnect(&mut self) {
+        let reconnect_policy = self.controller_settings.reconnect_policy();
+        if !self.awaiting_reconnect {
+            // Held buttons never see their release, so let go of everything they pressed
+            self.game_action_handler.release_all();
+            if reconnect_policy == ReconnectPolicy::StayPaused {
+                println!("Controller disconnected, pausing controller input");
+                self.pause_game_input();
+                return
+            }
+            println!("Controller disconnected, waiting for it to reconnect");
+            self.awaiting_reconnect = true;
+        }
+        if reconnect_policy == ReconnectPolicy::AnyController {
+            self.gamepad_manager.connect_to_available_controller();
+        }
+        if !self.gamepad_manager.is_controller_connected() {
+            return
+        }
+        self.awaiting_reconnect = false;
+        self.gamepad_manager.controller_state.clear_input();
+        if reconnect_policy == ReconnectPolicy::AnyController || self.gamepad_manager.connected_controller_uuid() == self.gamepad_manager.lost_controller() {
+            println!("Controller reconnected, resuming controller input");
+        } else {
+            println!("A different controller connected, pausing controller input");
+            self.pause_game_input();
+        }
+    }
+
+    // Input only reaches the game while it has focus, so alt-tabbing doesn't type into other apps.
+    // Held keys stay held for the grace period so brief focus flickers (like clicking the remote) don't drop them,
+    // and controller events wait for focus to come back. After that everything is released and events are thrown away.
+    fn game_accepts_input (&mut self) -> bool {
+        if self.game_window_tracker.borrow().is_game_active() {
+            self.game_unfocused_since = None;
+            if self.input_suspended {
+                println!("Game focused, resuming controller input");
+                self.input_suspended = false;
+            }
+            return true
+        }
+        let unfocused_since = *self.game_unfocused_since.get_or_insert_with(Instant::now);
+        if !self.input_suspended && unfocused_since.elapsed() >= Duration::from_millis(self.input_safety_settings.focus_grace_period_ms()) {
+            self.game_action_handler.release_all();
+            self.input_suspended = true;
         }
+        if self.input_suspended {
+            self.gamepad_manager.controller_state.clear_button_events();
+        }
+        false
+    }
+
+    // Hard-coded rather than bound, so it works whatever the button mapping is.
+    // Back and start stop sending their own bindings once both are down, and whichever went down first is let go.
+    fn emergency_stop_chord_held (&mut self) -> bool {
+        let mut buttons = self.gamepad_manager.controller_state.get_all_buttons();
+        if !(buttons["back"].held && buttons["start"].held) {
+            self.emergency_chord_since = None;
+            return false
+        }
+        if self.emergency_chord_since.is_none() {
+            for button_name in ["back", "start"] {
+                if let Some(button) = buttons.get_mut(button_name) {
+                    // Nothing was sent yet for a button pressed this frame
+                    button.just_unpressed = !button.just_pressed;
+                    button.just_pressed = false;
+                }
+            }
+        }
+        let chord_held_since = *self.emergency_chord_since.get_or_insert_with(Instant::now);
+        chord_held_since.elapsed() >= Duration::from_secs(1)
+    }
+
+    fn handle_controller_input_loop (&mut self, ctx: &Context) {
+        self.game_action_handler.follow_active_game();
         self.game_action_handler.process_input_buttons(self.gamepad_manager.controller_state.get_all_buttons());
         self.game_action_handler.process_input_analogs(self.gamepad_manager.controller_state.get_left_analog_stick(), 
                                             self.gamepad_manager.controller_state.get_right_analog_stick());
@@ -320,33 +675,74 @@ impl UserApp<egui_window_glfw_passthrough::GlfwWindow, WgpuBackend> for GameOver
         glfw_backend: &mut egui_window_glfw_passthrough::GlfwWindow,
         _: &mut WgpuBackend,
     ) {
-        glfw_backend.window.set_size(self.overlay_settings.screen_width() as i32, self.overlay_settings.screen_height() as i32);
+        // Everything below reads focus and window geometry from this
+        self.game_window_tracker.borrow_mut().refresh();
+        self.update_screen_rect(glfw_backend);
+
+        glfw_backend.window.set_size(self.window_rect.width() as i32, self.window_rect.height() as i32);
         glfw_backend.window.set_resizable(false);
         glfw_backend.window.set_decorated(false);
-        glfw_backend.window.set_pos(0, 0);
+        glfw_backend.window.set_pos(self.window_rect.min.x as i32, self.window_rect.min.y as i32);
+        // egui only picks up a new monitor's scaling a while after the overlay moves there, draw at the game monitor's from the start.
+        // The backend divides cursor positions and the screen rect by its own scale, so it has to agree with egui's or hover_pos is off.
+        let scale_factor = self.game_window_tracker.borrow().screen_coordinates().scale_factor();
+        if glfw_backend.scale != scale_factor || egui_context.pixels_per_point() != scale_factor {
+            glfw_backend.scale = scale_factor;
+            glfw_backend.raw_input.pixels_per_point = Some(scale_factor);
+            let [physical_width, physical_height] = glfw_backend.size_physical_pixels;
+            glfw_backend.raw_input.screen_rect = Some(Rect::from_min_size(Pos2::ZERO, Vec2 { x: physical_width as f32 / scale_factor, y: physical_height as f32 / scale_factor }));
+            // Otherwise hover_pos keeps the old scale until the cursor next moves
+            let [cursor_x, cursor_y] = glfw_backend.cursor_pos_physical_pixels;
+            glfw_backend.raw_input.events.push(egui::Event::PointerMoved(Pos2 { x: cursor_x / scale_factor, y: cursor_y / scale_factor }));
+            egui_context.set_pixels_per_point(scale_factor);
+        }
+        let overlay_visible = self.overlay_settings.always_show_overlay() || self.game_window_tracker.borrow().is_game_active();
 
         self.draw_remote(egui_context);
 
         // Make sure we process gamepad events no matter what, lest we lose disconnections and connections.
+        let previous_controller = self.gamepad_manager.connected_controller_uuid();
         self.gamepad_manager.process_gamepad_events();
-        if self.game_input_started {
-            if self.overlay_settings.windowed_mode() && self.game_window_tracker.is_poe_active() {
-                self.game_window_tracker.update_window_tracker();
+        // Switching to the preferred controller as it's plugged in lets go of whatever the old one held
+        if previous_controller.is_some() && self.gamepad_manager.is_controller_connected() && self.gamepad_manager.connected_controller_uuid() != previous_controller {
+            self.game_action_handler.release_all();
+        }
+        if self.calibration_wizard.is_some() {
+            if self.gamepad_manager.is_controller_connected() {
+                self.handle_calibration(egui_context);
+            } else {
+                self.stop_calibration(false);
             }
-            if self.overlay_settings.show_buttons() && (self.overlay_settings.always_show_overlay() || self.game_window_tracker.is_poe_active()) {
+        } else if self.game_input_started && self.emergency_stop_chord_held() {
+            println!("Emergency stop, releasing all input and pausing controller input");
+            self.pause_game_input();
+        } else if self.game_input_started {
+            if self.overlay_settings.show_buttons() && overlay_visible {
                 self.place_flask_overlay_images(egui_context, &self.overlay_images);
                 self.place_face_overlay_images(egui_context, &self.overlay_images);
                 self.place_mouse_button_overlay_images(egui_context, &self.overlay_images);
             }
 
-            if self.overlay_settings.show_crosshair() && (self.overlay_settings.always_show_overlay() || self.game_window_tracker.is_poe_active()) {
+            if self.overlay_settings.show_crosshair() && overlay_visible {
                 self.paint_crosshair(egui_context);
+                // The debug overlay paints its own rings
+                if self.overlay_settings.show_aim_rings() && !self.overlay_settings.show_debug_overlay() {
+                    self.paint_aim_rings(egui_context);
+                }
+            }
+
+            if self.overlay_settings.show_debug_overlay() && overlay_visible {
+                self.paint_debug_overlay(egui_context);
             }
 
-            self.handle_controller_input_loop(egui_context);
-            if !self.gamepad_manager.is_controller_connected() {
-                self.game_input_started = false;
-                self.remote_open = true;
+            if overlay_visible {
+                self.paint_control_mode(egui_context);
+            }
+
+            if self.awaiting_reconnect || !self.gamepad_manager.is_controller_connected() {
+                self.wait_for_reconnect();
+            } else if self.game_accepts_input() {
+                self.handle_controller_input_loop(egui_context);
             }
         }
         
@@ -373,21 +769,59 @@ impl UserApp<egui_window_glfw_passthrough::GlfwWindow, WgpuBackend> for GameOver
     }
 }
 
-pub fn start_overlay(overlay_settings: OverlaySettings, controller_settings: ControllerSettings, gamepad_manager: GamepadManager, game_action_handler: ActionManager, game_window_tracker: GameWindowTracker) {
-    let screen_width = overlay_settings.screen_width();
-    let screen_height = overlay_settings.screen_height();
+// Size, position and display scaling of the monitor the game was last seen on, or the primary monitor before we've seen it
+fn detect_game_monitor(glfw_backend: &mut egui_window_glfw_passthrough::GlfwWindow, game_window_center: Option<(f32, f32)>) -> Option<(Rect, f32)> {
+    glfw_backend.glfw.with_connected_monitors(|_, monitors| {
+        // GLFW always lists the primary monitor first
+        let game_monitors: Vec<(Rect, f32)> = monitors.iter()
+                                                .filter_map(|monitor| {
+                                                    let (monitor_x, monitor_y) = monitor.get_pos();
+                                                    let (scale_factor, _) = monitor.get_content_scale();
+                                                    monitor.get_video_mode().map(|video_mode| (Rect::from_min_size(Pos2 { x: monitor_x as f32, y: monitor_y as f32 },
+                                                                                                                   Vec2 { x: video_mode.width as f32, y: video_mode.height as f32 }),
+                                                                                               scale_factor))
+                                                })
+                                                .collect();
+        game_window_center.and_then(|(center_x, center_y)| game_monitors.iter().find(|(monitor_rect, _)| monitor_rect.contains(Pos2 { x: center_x, y: center_y })).copied())
+                          .or(game_monitors.first().copied())
+    })
+}
+
+// The overlay window in egui points
+fn overlay_rect(window_rect: Rect, scale_factor: f32) -> Rect {
+    Rect::from_min_size(Pos2::ZERO, window_rect.size() / scale_factor)
+}
+
+fn default_remote_pos(overlay_rect: Rect) -> Pos2 {
+    Pos2 { x: overlay_rect.width() / 2.0, y: overlay_rect.height() / 16.0 }
+}
+
+pub fn start_overlay(overlay_settings: OverlaySettings, controller_settings: ControllerSettings, input_safety_settings: InputSafetySettings, gamepad_manager: GamepadManager, game_action_handler: ActionManager, game_window_tracker: Rc<RefCell<GameWindowTracker>>) {
+    let screen_size = overlay_settings.screen_size_override();
+    // Nothing until the first frame finds the game's monitor, unless the size is configured
+    let window_rect = match screen_size {
+        Some((screen_width, screen_height)) => Rect::from_min_size(Pos2::ZERO, Vec2 { x: screen_width, y: screen_height }),
+        None => Rect::NOTHING,
+    };
     let game_overlay = GameOverlay{
         game_window_tracker: game_window_tracker,
         overlay_settings: overlay_settings,
-        window_rect: Rect::from_two_pos(Pos2 { x: 0.0, y: 0.0 }, Pos2 {x: screen_width, y: screen_height}), // This should really be screen rect
+        window_rect: window_rect,
         overlay_images: OverlayImages::default(),
         controller_settings: controller_settings,
+        input_safety_settings: input_safety_settings,
         gamepad_manager: gamepad_manager,
         game_action_handler: game_action_handler,
         remote_open: true,
-        remote_pos: Pos2 { x: screen_width / 2.0 , y: screen_height / 16.0 },
+        remote_pos: if window_rect.is_positive() {default_remote_pos(window_rect)} else {Pos2::ZERO},
         game_input_started: false,
+        calibration_wizard: None,
+        game_unfocused_since: None,
+        input_suspended: false,
+        emergency_chord_since: None,
+        awaiting_reconnect: false,
+        controller_tester: ControllerTester::default(),
     };
 
-    egui_overlay::start_egui_overlay(game_overlay, screen_width as i32, screen_height as i32);
+    egui_overlay::start_egui_overlay(game_overlay, screen_size.map(|(screen_width, screen_height)| (screen_width as i32, screen_height as i32)));
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
 pub mod game_overlay;
 mod egui_overlay;
-mod egui_render_wgpu;
\ No newline at end of file
+mod egui_render_wgpu;
+mod calibration;
+mod controller_tester;
@@ -0,0 +1,52 @@
+// Window positions, monitor rects, ring radii and rdev's cursor moves are all desktop pixels.
+// egui draws in points, which are desktop pixels divided by the display scaling of the monitor the overlay covers,
+// so anything crossing between the two goes through here.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ScreenCoordinates {
+    // Top left of the overlay in global desktop coordinates
+    origin_x: f32,
+    origin_y: f32,
+    // 1.5 on a 150% scaled monitor
+    scale_factor: f32,
+}
+
+impl Default for ScreenCoordinates {
+    fn default() -> Self {
+        ScreenCoordinates { origin_x: 0.0, origin_y: 0.0, scale_factor: 1.0 }
+    }
+}
+
+impl ScreenCoordinates {
+    pub fn new(origin_x: f32, origin_y: f32, scale_factor: f32) -> ScreenCoordinates {
+        // Some platforms report 0 for monitors they can't read the scaling of
+        let scale_factor = if scale_factor > 0.0 {scale_factor} else {1.0};
+        ScreenCoordinates { origin_x, origin_y, scale_factor }
+    }
+    pub fn scale_factor(&self) -> f32 {self.scale_factor}
+
+    pub fn to_overlay(&self, desktop_x: f32, desktop_y: f32) -> (f32, f32) {
+        ((desktop_x - self.origin_x) / self.scale_factor, (desktop_y - self.origin_y) / self.scale_factor)
+    }
+
+    pub fn to_desktop(&self, overlay_x: f32, overlay_y: f32) -> (f32, f32) {
+        (overlay_x * self.scale_factor + self.origin_x, overlay_y * self.scale_factor + self.origin_y)
+    }
+
+    // For radii and sizes, which don't move with the origin
+    pub fn to_overlay_length(&self, desktop_length: f32) -> f32 {
+        desktop_length / self.scale_factor
+    }
+}
+
+// rdev's Windows MouseMove counts from the top left of the virtual desktop instead of the primary monitor
+#[cfg(target_os = "windows")]
+pub fn to_input_pos(desktop_x: f64, desktop_y: f64) -> (f64, f64) {
+    use winapi::um::winuser::{GetSystemMetrics, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN};
+    let (virtual_screen_x, virtual_screen_y) = unsafe { (GetSystemMetrics(SM_XVIRTUALSCREEN), GetSystemMetrics(SM_YVIRTUALSCREEN)) };
+    (desktop_x - virtual_screen_x as f64, desktop_y - virtual_screen_y as f64)
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn to_input_pos(desktop_x: f64, desktop_y: f64) -> (f64, f64) {
+    (desktop_x, desktop_y)
+}
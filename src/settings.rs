@@ -1,53 +1,407 @@
-use std::{collections::{HashMap, HashSet}, process::exit};
+use std::{collections::{HashMap, HashSet}, fmt, fs, process::exit};
 
 use config::{Config, ConfigError};
 use native_dialog::MessageDialog;
-use serde::Deserialize;
-use crate::controller::input::ControllerTypeDetection;
+use serde::{Deserialize, Deserializer, de};
+use crate::controller::input::{ButtonRemap, ControllerType, ControllerTypeDetection, ReconnectPolicy};
+use crate::controller::grid_navigator::StashTabType;
+use crate::controller::action_manager::HeldActionPriority;
+use crate::window_finder::WindowInsets;
 
 #[derive(Clone, Deserialize)]
 pub struct OverlaySettings {
-    screen_height: f32,
-    screen_width: f32,
+    // Detected from the game's monitor unless both are set
+    #[serde(default)]
+    screen_height: Option<f32>,
+    #[serde(default)]
+    screen_width: Option<f32>,
     show_crosshair: bool,
+    #[serde(default)]
+    show_aim_rings: bool,
+    #[serde(default)]
+    show_debug_overlay: bool,
     show_buttons: bool,
     always_show_overlay: bool,
     windowed_mode: bool,
+    #[serde(default = "default_window_refresh_interval_ms")]
+    window_refresh_interval_ms: u64,
+    // Detected where the platform allows, set this to override
+    #[serde(default)]
+    window_insets: Option<WindowInsets>,
 }
 
+fn default_window_refresh_interval_ms() -> u64 {100}
+
 impl OverlaySettings {
-    pub fn screen_height(&self) -> f32 {self.screen_height}
-    pub fn screen_width(&self) -> f32 {self.screen_width}
+    pub fn screen_size_override(&self) -> Option<(f32, f32)> {
+        match (self.screen_width, self.screen_height) {
+            (Some(screen_width), Some(screen_height)) => Some((screen_width, screen_height)),
+            _ => None,
+        }
+    }
     pub fn show_crosshair(&self) -> bool {self.show_crosshair}
+    pub fn show_aim_rings(&self) -> bool {self.show_aim_rings}
+    pub fn show_debug_overlay(&self) -> bool {self.show_debug_overlay}
     pub fn show_buttons(&self) -> bool {self.show_buttons}
     pub fn always_show_overlay(&self) -> bool {self.always_show_overlay}
     pub fn windowed_mode(&self) -> bool {self.windowed_mode}
+    pub fn window_refresh_interval_ms(&self) -> u64 {self.window_refresh_interval_ms}
+    pub fn window_insets(&self) -> Option<WindowInsets> {self.window_insets}
+}
+
+// Either absolute pixels, or a percentage of the game window height (e.g. "10%") that follows the game as it's resized
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScreenDistance {
+    Pixels(f32),
+    WindowHeightFraction(f32),
+}
+
+impl ScreenDistance {
+    pub fn parse(distance: &str) -> Option<ScreenDistance> {
+        let distance = distance.trim();
+        // NaN and infinity would aim nowhere in particular
+        let parse_number = |number: &str| number.trim().parse::<f32>().ok().filter(|number| number.is_finite());
+        if let Some(percentage) = distance.strip_suffix('%') {
+            parse_number(percentage).map(|percentage| ScreenDistance::WindowHeightFraction(percentage / 100.0))
+        } else {
+            parse_number(distance.strip_suffix("px").unwrap_or(distance)).map(ScreenDistance::Pixels)
+        }
+    }
+
+    pub fn resolve(&self, game_window_height: f32) -> f32 {
+        match self {
+            ScreenDistance::Pixels(pixels) => *pixels,
+            ScreenDistance::WindowHeightFraction(fraction) => fraction * game_window_height,
+        }
+    }
+
+    // Same unit as self, so calibrating a percentage keeps it a percentage
+    pub fn with_pixels(&self, pixels: f32, game_window_height: f32) -> ScreenDistance {
+        match self {
+            ScreenDistance::Pixels(_pixels) => ScreenDistance::Pixels(pixels),
+            ScreenDistance::WindowHeightFraction(_fraction) => ScreenDistance::WindowHeightFraction(pixels / game_window_height),
+        }
+    }
+
+    // How it's written back to settings.toml, in the same unit and unrounded so saving and reloading gives the same value
+    pub fn to_setting_value(&self) -> String {
+        match self {
+            ScreenDistance::Pixels(pixels) => format!("\"{}\"", pixels),
+            ScreenDistance::WindowHeightFraction(fraction) => format!("\"{}%\"", fraction * 100.0),
+        }
+    }
+
+    // NaN counts too, it would aim nowhere in particular
+    pub fn is_negative(&self) -> bool {
+        match self {
+            ScreenDistance::Pixels(value) | ScreenDistance::WindowHeightFraction(value) => !(*value >= 0.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ScreenDistance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ScreenDistanceVisitor;
+
+        impl<'de> de::Visitor<'de> for ScreenDistanceVisitor {
+            type Value = ScreenDistance;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number of pixels or a percentage of the game window height like \"10%\"")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ScreenDistance, E> {
+                ScreenDistance::parse(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ScreenDistance, E> {
+                Ok(ScreenDistance::Pixels(value as f32))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ScreenDistance, E> {
+                Ok(ScreenDistance::Pixels(value as f32))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<ScreenDistance, E> {
+                Ok(ScreenDistance::Pixels(value as f32))
+            }
+        }
+
+        deserializer.deserialize_any(ScreenDistanceVisitor)
+    }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct ControllerSettings {
     #[serde(rename(deserialize = "dead_zone_percentage"))]
     controller_deadzone: f32,
-    character_x_offset_px: f32,
-    character_y_offset_px: f32,
-    walk_circle_radius_px: f32,
-    close_circle_radius_px: f32,
-    mid_circle_radius_px: f32,
-    far_circle_radius_px: f32,
+    #[serde(alias = "character_x_offset_px")]
+    character_x_offset: ScreenDistance,
+    #[serde(alias = "character_y_offset_px")]
+    character_y_offset: ScreenDistance,
+    #[serde(alias = "walk_circle_radius_px")]
+    walk_circle_radius: ScreenDistance,
+    #[serde(alias = "close_circle_radius_px")]
+    close_circle_radius: ScreenDistance,
+    #[serde(alias = "mid_circle_radius_px")]
+    mid_circle_radius: ScreenDistance,
+    #[serde(alias = "far_circle_radius_px")]
+    far_circle_radius: ScreenDistance,
+    // Older settings files have circular rings
+    #[serde(default = "default_ring_aspect_ratio")]
+    ring_aspect_ratio: f32,
+    #[serde(default)]
+    ring_rotation_degrees: f32,
     free_mouse_sensitivity_px: f32,
     controller_type: ControllerTypeDetection,
+    // UUID of the gamepad picked in the remote, so other devices don't get connected instead
+    #[serde(default)]
+    preferred_controller: Option<String>,
+    #[serde(default)]
+    reconnect_policy: ReconnectPolicy,
+    #[serde(default)]
+    remap: ButtonRemap,
+    // Keyed by controller UUID or family name, these replace remap for matching controllers
+    #[serde(default)]
+    controller_remaps: HashMap<String, ButtonRemap>,
+    // Extra SDL gamecontrollerdb.txt files, for pads gilrs doesn't have a mapping for
+    #[serde(default)]
+    mapping_files: Vec<String>,
+    #[serde(default = "default_bundled_mappings")]
+    bundled_mappings: bool,
 }
 
+fn default_ring_aspect_ratio() -> f32 {1.0}
+fn default_bundled_mappings() -> bool {true}
+
 impl ControllerSettings {
     pub fn controller_deadzone(&self) -> f32 {self.controller_deadzone}
-    pub fn character_x_offset_px(&self) -> f32 {self.character_x_offset_px}
-    pub fn character_y_offset_px(&self) -> f32 {self.character_y_offset_px}
-    pub fn walk_circle_radius_px(&self) -> f32 {self.walk_circle_radius_px}
-    pub fn close_circle_radius_px(&self) -> f32 {self.close_circle_radius_px}
-    pub fn mid_circle_radius_px(&self) -> f32 {self.mid_circle_radius_px}
-    pub fn far_circle_radius_px(&self) -> f32 {self.far_circle_radius_px}
+    // Distances are resolved against the current game window height, since windowed mode can resize it
+    pub fn character_x_offset_px(&self, game_window_height: f32) -> f32 {self.character_x_offset.resolve(game_window_height)}
+    pub fn character_y_offset_px(&self, game_window_height: f32) -> f32 {self.character_y_offset.resolve(game_window_height)}
+    pub fn walk_circle_radius_px(&self, game_window_height: f32) -> f32 {self.walk_circle_radius.resolve(game_window_height)}
+    pub fn close_circle_radius_px(&self, game_window_height: f32) -> f32 {self.close_circle_radius.resolve(game_window_height)}
+    pub fn mid_circle_radius_px(&self, game_window_height: f32) -> f32 {self.mid_circle_radius.resolve(game_window_height)}
+    pub fn far_circle_radius_px(&self, game_window_height: f32) -> f32 {self.far_circle_radius.resolve(game_window_height)}
+    pub fn set_character_offset_px(&mut self, x_offset: f32, y_offset: f32, game_window_height: f32) {
+        self.character_x_offset = self.character_x_offset.with_pixels(x_offset, game_window_height);
+        self.character_y_offset = self.character_y_offset.with_pixels(y_offset, game_window_height);
+    }
+    pub fn set_walk_circle_radius_px(&mut self, radius: f32, game_window_height: f32) {self.walk_circle_radius = self.walk_circle_radius.with_pixels(radius, game_window_height)}
+    pub fn set_close_circle_radius_px(&mut self, radius: f32, game_window_height: f32) {self.close_circle_radius = self.close_circle_radius.with_pixels(radius, game_window_height)}
+    pub fn set_mid_circle_radius_px(&mut self, radius: f32, game_window_height: f32) {self.mid_circle_radius = self.mid_circle_radius.with_pixels(radius, game_window_height)}
+    pub fn set_far_circle_radius_px(&mut self, radius: f32, game_window_height: f32) {self.far_circle_radius = self.far_circle_radius.with_pixels(radius, game_window_height)}
+    pub fn ring_aspect_ratio(&self) -> f32 {self.ring_aspect_ratio}
+    pub fn ring_rotation_degrees(&self) -> f32 {self.ring_rotation_degrees}
     pub fn free_mouse_sensitivity_px(&self) -> f32 {self.free_mouse_sensitivity_px}
     pub fn controller_type(&self) -> ControllerTypeDetection {self.controller_type.clone()}
+    pub fn preferred_controller(&self) -> Option<String> {self.preferred_controller.clone()}
+    pub fn set_preferred_controller(&mut self, controller_uuid: String) {self.preferred_controller = Some(controller_uuid)}
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {self.reconnect_policy}
+    pub fn mapping_files(&self) -> Vec<String> {self.mapping_files.clone()}
+    pub fn bundled_mappings(&self) -> bool {self.bundled_mappings}
+    // A remap for this exact controller wins over one for its family, which wins over the default
+    pub fn button_remap(&self, controller_uuid: &str, controller_type: ControllerType) -> ButtonRemap {
+        let controller_family = format!("{:?}", controller_type);
+        self.controller_remaps.get(controller_uuid)
+            .or(self.controller_remaps.iter().find(|(key, _)| key.eq_ignore_ascii_case(&controller_family)).map(|(_, button_remap)| button_remap))
+            .copied()
+            .unwrap_or(self.remap)
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangePolicy {
+    StickMagnitude,
+    ClampCursor,
+}
+
+impl Default for RangePolicy {
+    fn default() -> Self {RangePolicy::StickMagnitude}
+}
+
+// action_distances entries can be a named ring or "cursor", a radius in pixels, or a min/max range
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ActionDistanceSetting {
+    Named(String),
+    Radius(f32),
+    Range {
+        min: ScreenDistance,
+        max: ScreenDistance,
+        #[serde(default)]
+        policy: RangePolicy,
+    },
+}
+
+// Settings files from before cursor mode don't have this section, so everything has a default
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CursorModeSettings {
+    cursor_speed_px: f32,
+    button_mapping: HashMap<String, String>,
+}
+
+impl Default for CursorModeSettings {
+    // No rebinds, every button keeps its button_mapping binding
+    fn default() -> Self {
+        CursorModeSettings { cursor_speed_px: 15.0, button_mapping: HashMap::new() }
+    }
+}
+
+impl CursorModeSettings {
+    pub fn cursor_speed_px(&self) -> f32 {self.cursor_speed_px}
+    pub fn button_mapping(&self) -> HashMap<String, String> {self.button_mapping.clone()}
+}
+
+// Positions and sizes are fractions of the game window width (x) and height (y)
+#[derive(Clone, Copy, Deserialize)]
+pub struct InventoryGridSettings {
+    origin_x: f32,
+    origin_y: f32,
+    cell_width: f32,
+    cell_height: f32,
+    columns: u32,
+    rows: u32,
+}
+
+impl InventoryGridSettings {
+    pub fn origin_x(&self) -> f32 {self.origin_x}
+    pub fn origin_y(&self) -> f32 {self.origin_y}
+    pub fn cell_width(&self) -> f32 {self.cell_width}
+    pub fn cell_height(&self) -> f32 {self.cell_height}
+    pub fn columns(&self) -> u32 {self.columns}
+    pub fn rows(&self) -> u32 {self.rows}
+}
+
+// Unset grids default to PoE's layout at 16:9, the same values settings.toml ships with
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct GridNavigationSettings {
+    stash_tab: StashTabType,
+    inventory: InventoryGridSettings,
+    standard_stash: InventoryGridSettings,
+    quad_stash: InventoryGridSettings,
+    currency_stash: InventoryGridSettings,
+}
+
+impl Default for GridNavigationSettings {
+    fn default() -> Self {
+        GridNavigationSettings {
+            stash_tab: StashTabType::Standard,
+            inventory: InventoryGridSettings { origin_x: 0.663, origin_y: 0.5426, cell_width: 0.02734, cell_height: 0.04861, columns: 12, rows: 5 },
+            standard_stash: InventoryGridSettings { origin_x: 0.0083, origin_y: 0.1481, cell_width: 0.02734, cell_height: 0.04861, columns: 12, rows: 12 },
+            quad_stash: InventoryGridSettings { origin_x: 0.0083, origin_y: 0.1481, cell_width: 0.01367, cell_height: 0.02431, columns: 24, rows: 24 },
+            currency_stash: InventoryGridSettings { origin_x: 0.0156, origin_y: 0.1574, cell_width: 0.03125, cell_height: 0.0787, columns: 10, rows: 7 },
+        }
+    }
+}
+
+impl GridNavigationSettings {
+    pub fn stash_tab(&self) -> StashTabType {self.stash_tab}
+    pub fn inventory(&self) -> InventoryGridSettings {self.inventory}
+    pub fn standard_stash(&self) -> InventoryGridSettings {self.standard_stash}
+    pub fn quad_stash(&self) -> InventoryGridSettings {self.quad_stash}
+    pub fn currency_stash(&self) -> InventoryGridSettings {self.currency_stash}
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct UiStateSettings {
+    automatic_mode_switching: bool,
+    inventory_keys: Vec<String>,
+    panel_keys: Vec<String>,
+    close_keys: Vec<String>,
+}
+
+impl Default for UiStateSettings {
+    // Settings files from before this section only switch modes with "ToggleCursorMode"
+    fn default() -> Self {
+        UiStateSettings {
+            automatic_mode_switching: false,
+            inventory_keys: vec!["i".to_owned()],
+            panel_keys: ["p", "c", "g", "h", "u"].map(|key| key.to_owned()).to_vec(),
+            close_keys: vec!["escape".to_owned(), "space".to_owned()],
+        }
+    }
+}
+
+impl UiStateSettings {
+    pub fn automatic_mode_switching(&self) -> bool {self.automatic_mode_switching}
+    pub fn inventory_keys(&self) -> Vec<String> {self.inventory_keys.clone()}
+    pub fn panel_keys(&self) -> Vec<String> {self.panel_keys.clone()}
+    pub fn close_keys(&self) -> Vec<String> {self.close_keys.clone()}
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct InputSafetySettings {
+    focus_grace_period_ms: u64,
+    max_key_hold_ms: u64,
+}
+
+impl Default for InputSafetySettings {
+    // The stuck input watchdog stays off unless settings.toml turns it on
+    fn default() -> Self {
+        InputSafetySettings { focus_grace_period_ms: 250, max_key_hold_ms: 0 }
+    }
+}
+
+impl InputSafetySettings {
+    pub fn focus_grace_period_ms(&self) -> u64 {self.focus_grace_period_ms}
+    pub fn max_key_hold_ms(&self) -> u64 {self.max_key_hold_ms}
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleMatch {
+    Exact,
+    Substring,
+    Regex,
+}
+
+fn default_title_match() -> TitleMatch {TitleMatch::Exact}
+
+// A game whose window the overlay follows, and the profile to use while it's focused
+#[derive(Clone, Deserialize)]
+pub struct GameSettings {
+    name: String,
+    title: String,
+    #[serde(default = "default_title_match")]
+    title_match: TitleMatch,
+    #[serde(default)]
+    process_name: Option<String>,
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+impl GameSettings {
+    pub fn name(&self) -> String {self.name.clone()}
+    pub fn title(&self) -> String {self.title.clone()}
+    pub fn title_match(&self) -> TitleMatch {self.title_match}
+    pub fn process_name(&self) -> Option<String> {self.process_name.clone()}
+}
+
+fn default_games() -> Vec<GameSettings> {
+    vec![GameSettings {
+        name: "Path of Exile".to_owned(),
+        title: "Path of Exile".to_owned(),
+        title_match: TitleMatch::Exact,
+        process_name: None,
+        profile: None,
+    }]
+}
+
+// Overrides applied on top of the top-level bindings while a game using this profile is focused
+#[derive(Clone, Deserialize)]
+pub struct ProfileSettings {
+    #[serde(default)]
+    button_mapping: HashMap<String, String>,
+    #[serde(default)]
+    aimable_buttons: Option<Vec<String>>,
+    #[serde(default)]
+    action_distances: Option<HashMap<String, ActionDistanceSetting>>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -59,9 +413,27 @@ pub struct ApplicationSettings {
     #[serde(skip_deserializing)]
     ability_mapping_settings: HashMap<String, String>,
     aimable_buttons: Vec<String>,
-    action_distances: HashMap<String, String>,
+    action_distances: HashMap<String, ActionDistanceSetting>,
+    #[serde(default)]
+    held_action_priority: HeldActionPriority,
+    #[serde(default)]
+    ability_priority: Vec<String>,
+    #[serde(default)]
+    ability_keys: Vec<String>,
     #[serde(rename(deserialize = "controller"))]
     controller_settings: ControllerSettings,
+    #[serde(default, rename(deserialize = "cursor_mode"))]
+    cursor_mode_settings: CursorModeSettings,
+    #[serde(default, rename(deserialize = "grid_navigation"))]
+    grid_navigation_settings: GridNavigationSettings,
+    #[serde(default, rename(deserialize = "ui_state"))]
+    ui_state_settings: UiStateSettings,
+    #[serde(default, rename(deserialize = "input_safety"))]
+    input_safety_settings: InputSafetySettings,
+    #[serde(default = "default_games")]
+    games: Vec<GameSettings>,
+    #[serde(default)]
+    profiles: HashMap<String, ProfileSettings>,
 }
 
 impl ApplicationSettings {
@@ -69,31 +441,55 @@ impl ApplicationSettings {
     pub fn button_mapping_settings(&self) -> HashMap<String, String> {self.button_mapping_settings.clone()}
     pub fn ability_mapping_settings(&self) -> HashMap<String, String> {self.ability_mapping_settings.clone()}
     pub fn aimable_buttons(&self) -> Vec<String> {self.aimable_buttons.clone()}
-    pub fn action_distances(&self) -> HashMap<String, String> {self.action_distances.clone()}
+    pub fn action_distances(&self) -> HashMap<String, ActionDistanceSetting> {self.action_distances.clone()}
+    pub fn held_action_priority(&self) -> HeldActionPriority {self.held_action_priority}
+    pub fn ability_priority(&self) -> Vec<String> {self.ability_priority.clone()}
     pub fn controller_settings(&self) -> ControllerSettings {self.controller_settings.clone()}
+    pub fn set_controller_settings(&mut self, controller_settings: ControllerSettings) {self.controller_settings = controller_settings}
+    pub fn cursor_mode_settings(&self) -> CursorModeSettings {self.cursor_mode_settings.clone()}
+    pub fn grid_navigation_settings(&self) -> GridNavigationSettings {self.grid_navigation_settings.clone()}
+    pub fn ui_state_settings(&self) -> UiStateSettings {self.ui_state_settings.clone()}
+    pub fn input_safety_settings(&self) -> InputSafetySettings {self.input_safety_settings}
+    pub fn games(&self) -> Vec<GameSettings> {self.games.clone()}
+
+    pub fn profile_for_game(&self, game_name: &str) -> Option<String> {
+        self.games.iter().find(|game| game.name == game_name).and_then(|game| game.profile.clone())
+    }
+
+    // These settings with a profile's overrides applied
+    pub fn with_profile(&self, profile_name: &str) -> ApplicationSettings {
+        let mut settings = self.clone();
+        if let Some(profile) = self.profiles.get(profile_name) {
+            settings.button_mapping_settings.extend(profile.button_mapping.clone());
+            if let Some(aimable_buttons) = &profile.aimable_buttons {
+                settings.aimable_buttons = aimable_buttons.clone();
+            }
+            if let Some(action_distances) = &profile.action_distances {
+                settings.action_distances = action_distances.clone();
+            }
+            settings.ability_mapping_settings = settings.build_ability_mapping();
+        }
+        settings
+    }
 
     fn sanitize_settings(&mut self) {
+        // Only Windows can find the game window while it's unfocused, elsewhere the overlay would have no window bounds
+        #[cfg(not(target_os = "windows"))]
         if self.overlay_settings.always_show_overlay() && self.overlay_settings.windowed_mode() {
-            alert_and_exit_on_invalid_settings("Windowed Mode is unsupported when coupled with Always Show Overlay!");
-            panic!("Windowed Mode is unsupported when coupled with Always Show Overlay!");
+            alert_and_exit_on_invalid_settings("Windowed Mode coupled with Always Show Overlay is only supported on Windows!");
+            panic!("Windowed Mode coupled with Always Show Overlay is only supported on Windows!");
+        }
+        if self.overlay_settings.screen_width.is_some() != self.overlay_settings.screen_height.is_some() {
+            alert_and_exit_on_invalid_settings("Set both screen_width and screen_height to override the detected resolution, or neither!");
+            panic!("Set both screen_width and screen_height to override the detected resolution, or neither!");
         }
 
         let valid_ability_buttons: HashSet<String> = HashSet::from(["a", "b", "x", "y", "bumper_left", "bumper_right", "trigger_left", "trigger_right"].map(|x| x.to_owned()));
-        let valid_ability_ranges: HashSet<String>= HashSet::from(["close", "mid", "far"].map(|x| x.to_owned()));
-        let buttons: Vec<String> = self.action_distances.keys().cloned().collect();
-        let distances: Vec<String> = self.action_distances.values().cloned().collect();
-
-        // Ensure ability ranges!
-        for button in &buttons {
+        validate_action_distances(&self.action_distances, &valid_ability_buttons);
+        for button in &self.ability_priority {
             if !valid_ability_buttons.contains(button) {
-                alert_and_exit_on_invalid_settings(&format!("{:} is not a valid button ({:#?})", button, valid_ability_buttons));
-                panic!("{:} is not a valid button ({:#?})", button, valid_ability_buttons);
-            }
-        }
-        for distance in &distances {
-            if !valid_ability_ranges.contains(distance) {
-                alert_and_exit_on_invalid_settings(&format!("{:} is not a valid distance ({:#?})", distance, valid_ability_ranges));
-                panic!("{:} is not a valid distance ({:#?})", distance, valid_ability_ranges);
+                alert_and_exit_on_invalid_settings(&format!("{:} is not a valid ability_priority button ({:#?})", button, valid_ability_buttons));
+                panic!("{:} is not a valid ability_priority button ({:#?})", button, valid_ability_buttons);
             }
         }
 
@@ -122,6 +518,44 @@ impl ApplicationSettings {
             incorrect_keys(&button_mapping_key_set, &valid_buttons_set)
         }
 
+        // Cursor mode only needs to list the buttons it rebinds
+        for button in self.cursor_mode_settings.button_mapping.keys() {
+            if !valid_buttons_set.contains(button) {
+                alert_and_exit_on_invalid_settings(&format!("{:} is not a valid cursor_mode button ({:#?})", button, valid_buttons_set));
+                panic!("{:} is not a valid cursor_mode button ({:#?})", button, valid_buttons_set);
+            }
+        }
+
+        if self.controller_settings.ring_aspect_ratio <= 0.0 {
+            alert_and_exit_on_invalid_settings("ring_aspect_ratio must be greater than 0!");
+            panic!("ring_aspect_ratio must be greater than 0!");
+        }
+
+        for mapping_file in &self.controller_settings.mapping_files {
+            if !std::path::Path::new(mapping_file).is_file() {
+                alert_and_exit_on_invalid_settings(&format!("mapping_files entry {:} isn't a file", mapping_file));
+                panic!("mapping_files entry {:} isn't a file", mapping_file);
+            }
+        }
+
+        // Ensure grids have cells to snap to
+        for (grid_name, grid) in [("inventory", self.grid_navigation_settings.inventory),
+                                  ("standard_stash", self.grid_navigation_settings.standard_stash),
+                                  ("quad_stash", self.grid_navigation_settings.quad_stash),
+                                  ("currency_stash", self.grid_navigation_settings.currency_stash)] {
+            if grid.columns == 0 || grid.rows == 0 {
+                alert_and_exit_on_invalid_settings(&format!("grid_navigation {:} must have at least one column and one row", grid_name));
+                panic!("grid_navigation {:} must have at least one column and one row", grid_name);
+            }
+        }
+
+        // Key names are matched case-insensitively against what we send
+        for keys in [&mut self.ui_state_settings.inventory_keys, &mut self.ui_state_settings.panel_keys, &mut self.ui_state_settings.close_keys] {
+            for key in keys.iter_mut() {
+                *key = key.to_lowercase();
+            }
+        }
+
         // Ensure aimables
         let valid_aimable_buttons_set = HashSet::from(
             [
@@ -141,15 +575,119 @@ impl ApplicationSettings {
             }
         }
 
-        // Setup ability_mapping_settings
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("a").unwrap().clone(), "a".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("b").unwrap().clone(), "b".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("x").unwrap().clone(), "x".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("y").unwrap().clone(), "y".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("bumper_left").unwrap().clone(), "bumper_left".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("bumper_right").unwrap().clone(), "bumper_right".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("trigger_left").unwrap().clone(), "trigger_left".to_owned());
-        self.ability_mapping_settings.insert(self.button_mapping_settings.get("trigger_right").unwrap().clone(), "trigger_right".to_owned());
+        // Profiles only list what they change, but what they list must be valid
+        for (profile_name, profile) in &self.profiles {
+            for button in profile.button_mapping.keys() {
+                if !valid_buttons_set.contains(button) {
+                    alert_and_exit_on_invalid_settings(&format!("{:} is not a valid button in profile {:} ({:#?})", button, profile_name, valid_buttons_set));
+                    panic!("{:} is not a valid button in profile {:} ({:#?})", button, profile_name, valid_buttons_set);
+                }
+            }
+            for button in profile.aimable_buttons.iter().flatten() {
+                if !valid_aimable_buttons_set.contains(button) {
+                    alert_and_exit_on_invalid_settings(&format!("{:} is not a valid aimable button in profile {:} ({:#?})", button, profile_name, valid_aimable_buttons_set));
+                    panic!("{:} is not a valid aimable button in profile {:} ({:#?})", button, profile_name, valid_aimable_buttons_set);
+                }
+            }
+            if let Some(action_distances) = &profile.action_distances {
+                validate_action_distances(action_distances, &valid_ability_buttons);
+            }
+        }
+
+        // Ensure games can be matched, and their profiles exist
+        if self.games.is_empty() {
+            alert_and_exit_on_invalid_settings("At least one game must be configured in [[games]]!");
+            panic!("At least one game must be configured in [[games]]!");
+        }
+        for game in &self.games {
+            if game.title_match == TitleMatch::Regex {
+                if let Err(error) = regex::Regex::new(&game.title) {
+                    alert_and_exit_on_invalid_settings(&format!("{:} has an invalid title regex: {:}", game.name, error));
+                    panic!("{:} has an invalid title regex: {:}", game.name, error);
+                }
+            }
+            if let Some(profile_name) = &game.profile {
+                if !self.profiles.contains_key(profile_name) {
+                    alert_and_exit_on_invalid_settings(&format!("{:} uses profile {:}, which isn't in [profiles]", game.name, profile_name));
+                    panic!("{:} uses profile {:}, which isn't in [profiles]", game.name, profile_name);
+                }
+            }
+        }
+
+        self.ability_mapping_settings = self.build_ability_mapping();
+    }
+
+    // Keyed by lowercase action so held keys can be traced back to their button.
+    // Without explicit ability_keys, abilities are whatever is bound to the aimable and action distance buttons.
+    fn build_ability_mapping(&self) -> HashMap<String, String> {
+        let mut ability_mapping = HashMap::new();
+        let ability_actions: Vec<String> = if self.ability_keys.is_empty() {
+            self.aimable_buttons.iter()
+                .chain(self.action_distances.keys())
+                .map(|button| self.button_mapping_settings.get(button).unwrap().to_lowercase())
+                .filter(|action| action != "")
+                .collect()
+        } else {
+            self.ability_keys.iter().map(|action| action.to_lowercase()).collect()
+        };
+        // When several buttons share an action, aimable buttons win, then action distance buttons, then alphabetical order.
+        // Going through the HashMaps directly would pick a different button each run.
+        let mut sorted_distance_buttons: Vec<&String> = self.action_distances.keys().collect();
+        sorted_distance_buttons.sort();
+        let mut sorted_buttons: Vec<&String> = self.button_mapping_settings.keys().collect();
+        sorted_buttons.sort();
+        let candidate_buttons: Vec<&String> = self.aimable_buttons.iter()
+                                                    .chain(sorted_distance_buttons)
+                                                    .chain(sorted_buttons)
+                                                    .collect();
+        for action in ability_actions {
+            // An explicit ability key might not be bound to any button, it still counts as held but won't aim
+            let button = candidate_buttons.iter()
+                                .find(|button| self.button_mapping_settings.get(**button).map_or(false, |mapped_action| mapped_action.to_lowercase() == action))
+                                .map(|button| button.to_string())
+                                .unwrap_or(action.clone());
+            ability_mapping.insert(action, button);
+        }
+        ability_mapping
+    }
+}
+
+fn validate_action_distances(action_distances: &HashMap<String, ActionDistanceSetting>, valid_ability_buttons: &HashSet<String>) {
+    let valid_ability_ranges: HashSet<String>= HashSet::from(["close", "mid", "far", "cursor"].map(|x| x.to_owned()));
+
+    // Ensure ability ranges!
+    for button in action_distances.keys() {
+        if !valid_ability_buttons.contains(button) {
+            alert_and_exit_on_invalid_settings(&format!("{:} is not a valid button ({:#?})", button, valid_ability_buttons));
+            panic!("{:} is not a valid button ({:#?})", button, valid_ability_buttons);
+        }
+    }
+    for distance in action_distances.values() {
+        match distance {
+            // Quoted numbers and percentages are radii too, like the rest of settings.toml
+            ActionDistanceSetting::Named(name) => {
+                if !valid_ability_ranges.contains(name) && ScreenDistance::parse(name).is_none() {
+                    alert_and_exit_on_invalid_settings(&format!("{:} is not a valid distance ({:#?}, a number of pixels or a percentage of window height)", name, valid_ability_ranges));
+                    panic!("{:} is not a valid distance ({:#?}, a number of pixels or a percentage of window height)", name, valid_ability_ranges);
+                }
+                if ScreenDistance::parse(name).map_or(false, |radius| radius.is_negative()) {
+                    alert_and_exit_on_invalid_settings(&format!("Distance {:} can't be negative", name));
+                    panic!("Distance {:} can't be negative", name);
+                }
+            },
+            ActionDistanceSetting::Radius(radius) => {
+                if ScreenDistance::Pixels(*radius).is_negative() {
+                    alert_and_exit_on_invalid_settings(&format!("Distance {:?} can't be negative", radius));
+                    panic!("Distance {:?} can't be negative", radius);
+                }
+            },
+            ActionDistanceSetting::Range { min, max, policy: _ } => {
+                if min.is_negative() || max.is_negative() {
+                    alert_and_exit_on_invalid_settings(&format!("Distance range {:?}-{:?} can't be negative", min, max));
+                    panic!("Distance range {:?}-{:?} can't be negative", min, max);
+                }
+            },
+        }
     }
 }
 
@@ -187,6 +725,77 @@ pub fn alert_and_exit_on_invalid_settings(error_message: &str) {
     }
 }
 
+// Rewrites the calibrated [controller] values in place, so the rest of settings.toml keeps its comments and layout
+pub fn save_calibrated_controller_settings(controller_settings: &ControllerSettings) -> Result<(), std::io::Error> {
+    let calibrated_values = [
+        ("character_x_offset", controller_settings.character_x_offset),
+        ("character_y_offset", controller_settings.character_y_offset),
+        ("walk_circle_radius", controller_settings.walk_circle_radius),
+        ("close_circle_radius", controller_settings.close_circle_radius),
+        ("mid_circle_radius", controller_settings.mid_circle_radius),
+        ("far_circle_radius", controller_settings.far_circle_radius),
+    ];
+
+    save_controller_section_values(&calibrated_values.map(|(setting_name, distance)| (setting_name, distance.to_setting_value())))
+}
+
+pub fn save_preferred_controller(controller_uuid: &str) -> Result<(), std::io::Error> {
+    save_controller_section_values(&[("preferred_controller", format!("\"{}\"", controller_uuid))])
+}
+
+fn save_controller_section_values(values: &[(&str, String)]) -> Result<(), std::io::Error> {
+    let settings_file = fs::read_to_string("settings.toml")?;
+    fs::write("settings.toml", rewrite_controller_section(&settings_file, values))
+}
+
+// Rewrites just these keys in the [controller] section of a settings file, keeping everyone's comments, layout and line endings.
+// Keys that aren't in the file yet are added after the section's last setting.
+fn rewrite_controller_section(settings_file: &str, values: &[(&str, String)]) -> String {
+    let line_ending = if settings_file.contains("\r\n") {"\r\n"} else {"\n"};
+    let mut in_controller_section = false;
+    let mut saved_keys = HashSet::<&str>::new();
+    let mut last_controller_line = None;
+    let mut saved_lines = Vec::<String>::new();
+    for line in settings_file.lines() {
+        let trimmed_line = line.trim_start();
+        if trimmed_line.starts_with('[') {
+            in_controller_section = trimmed_line.starts_with("[controller]");
+        }
+        let mut saved_line = line.to_owned();
+        if in_controller_section {
+            if let Some((key, rest)) = trimmed_line.split_once('=') {
+                let key = key.trim();
+                last_controller_line = Some(saved_lines.len());
+                for (setting_name, value) in values {
+                    // Older settings files still use the *_px names
+                    if key == *setting_name || key.strip_suffix("_px") == Some(*setting_name) {
+                        let comment = rest.find('#').map(|comment_start| format!(" {}", &rest[comment_start..])).unwrap_or_default();
+                        saved_line = format!("{} = {}{}", key, value, comment);
+                        saved_keys.insert(*setting_name);
+                    }
+                }
+            }
+        }
+        saved_lines.push(saved_line);
+    }
+    let missing_lines: Vec<String> = values.iter()
+                                            .filter(|(setting_name, _)| !saved_keys.contains(setting_name))
+                                            .map(|(setting_name, value)| format!("{} = {}", setting_name, value))
+                                            .collect();
+    match last_controller_line {
+        Some(last_controller_line) => {saved_lines.splice(last_controller_line + 1..last_controller_line + 1, missing_lines);},
+        None if !missing_lines.is_empty() => {
+            saved_lines.push(String::from("[controller]"));
+            saved_lines.extend(missing_lines);
+        },
+        None => (),
+    }
+    if settings_file.ends_with('\n') {
+        saved_lines.push(String::new());
+    }
+    saved_lines.join(line_ending)
+}
+
 pub fn load_settings() -> ApplicationSettings {
     let settings = Config::builder()
                     .add_source(config::File::with_name("settings.toml"))
@@ -217,4 +826,76 @@ pub fn load_settings() -> ApplicationSettings {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pixels_and_percentages() {
+        assert_eq!(ScreenDistance::parse("110"), Some(ScreenDistance::Pixels(110.0)));
+        assert_eq!(ScreenDistance::parse(" 110px "), Some(ScreenDistance::Pixels(110.0)));
+        assert_eq!(ScreenDistance::parse("10%"), Some(ScreenDistance::WindowHeightFraction(0.1)));
+        assert_eq!(ScreenDistance::parse("12.5 %"), Some(ScreenDistance::WindowHeightFraction(0.125)));
+        assert_eq!(ScreenDistance::parse("ten"), None);
+        assert_eq!(ScreenDistance::parse("%"), None);
+    }
+
+    #[test]
+    fn rejects_nan_and_infinity() {
+        assert_eq!(ScreenDistance::parse("NaN"), None);
+        assert_eq!(ScreenDistance::parse("NaN%"), None);
+        assert_eq!(ScreenDistance::parse("inf"), None);
+        assert_eq!(ScreenDistance::parse("-infpx"), None);
+    }
+
+    #[test]
+    fn negative_and_nan_distances_count_as_negative() {
+        assert!(ScreenDistance::parse("-5").unwrap().is_negative());
+        assert!(ScreenDistance::parse("-5%").unwrap().is_negative());
+        assert!(ScreenDistance::Pixels(f32::NAN).is_negative());
+        assert!(ScreenDistance::WindowHeightFraction(f32::NAN).is_negative());
+        assert!(!ScreenDistance::parse("0").unwrap().is_negative());
+        assert!(!ScreenDistance::parse("10%").unwrap().is_negative());
+    }
 
+    #[test]
+    fn percentages_follow_the_window_height() {
+        let distance = ScreenDistance::parse("10%").unwrap();
+        assert!((distance.resolve(1080.0) - 108.0).abs() < 0.001);
+        assert!((distance.resolve(1440.0) - 144.0).abs() < 0.001);
+        assert_eq!(ScreenDistance::parse("110px").unwrap().resolve(1440.0), 110.0);
+    }
+
+    fn saved_distance(settings_file: &str, setting_name: &str) -> ScreenDistance {
+        let line = settings_file.lines().find(|line| line.split('=').next().map(|key| key.trim()) == Some(setting_name)).unwrap();
+        let value = line.split_once('=').unwrap().1.split('#').next().unwrap().trim().trim_matches('"');
+        ScreenDistance::parse(value).unwrap()
+    }
+
+    #[test]
+    fn calibration_save_keeps_units_line_endings_and_other_sections() {
+        let settings_file = "[overlay]\r\nshow_crosshair = true\r\n\r\n[controller]\r\ncharacter_y_offset = \"10%\" # Above the feet\r\nwalk_circle_radius_px = \"110\"\r\n\r\n[input_safety]\r\nmax_key_hold_ms = 5000\r\n";
+        let game_window_height = 1080.0;
+        let character_y_offset = ScreenDistance::parse("10%").unwrap().with_pixels(120.0, game_window_height);
+        let walk_circle_radius = ScreenDistance::parse("110").unwrap().with_pixels(130.5, game_window_height);
+        let far_circle_radius = ScreenDistance::Pixels(400.0);
+        let saved_file = rewrite_controller_section(settings_file, &[("character_y_offset", character_y_offset.to_setting_value()),
+                                                                     ("walk_circle_radius", walk_circle_radius.to_setting_value()),
+                                                                     ("far_circle_radius", far_circle_radius.to_setting_value())]);
+
+        assert!(saved_file.ends_with("\r\n"));
+        assert!(!saved_file.replace("\r\n", "").contains('\n'));
+        assert!(saved_file.starts_with("[overlay]\r\nshow_crosshair = true\r\n\r\n[controller]\r\n"));
+        assert!(saved_file.ends_with("\r\n\r\n[input_safety]\r\nmax_key_hold_ms = 5000\r\n"));
+        assert!(saved_file.contains("# Above the feet"));
+        // Older *_px names are kept, and settings missing from the file are added to the section
+        assert!(saved_file.contains("\r\nwalk_circle_radius_px = "));
+        assert!(saved_file.contains("\r\nfar_circle_radius = \"400\"\r\n\r\n[input_safety]"));
+
+        let saved_y_offset = saved_distance(&saved_file, "character_y_offset");
+        assert!(matches!(saved_y_offset, ScreenDistance::WindowHeightFraction(_)));
+        assert!((saved_y_offset.resolve(game_window_height) - 120.0).abs() < 0.001);
+        assert_eq!(saved_distance(&saved_file, "walk_circle_radius_px"), ScreenDistance::Pixels(130.5));
+        assert_eq!(saved_distance(&saved_file, "far_circle_radius"), far_circle_radius);
+    }
+}
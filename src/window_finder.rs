@@ -0,0 +1,145 @@
+use std::path::Path;
+
+// How far the game's client area sits inside its window frame (title bar, borders, drop shadow)
+#[derive(Clone, Copy, Default, PartialEq, Debug, serde::Deserialize)]
+pub struct WindowInsets {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+// Frame rectangle of a window, with its insets when the platform can tell us them
+#[derive(Clone, Copy)]
+pub struct WindowGeometry {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub insets: Option<WindowInsets>,
+}
+
+// active_win_pos_rs only knows about the focused window, this finds a game window that isn't focused
+#[cfg(target_os = "windows")]
+pub fn find_window(matches: &dyn Fn(&str, &Path) -> bool) -> Option<WindowGeometry> {
+    windows::find_window(matches)
+}
+
+// TODO: Other platforms can't look up unfocused windows yet, so settings reject always_show_overlay with windowed_mode there
+#[cfg(not(target_os = "windows"))]
+pub fn find_window(_matches: &dyn Fn(&str, &Path) -> bool) -> Option<WindowGeometry> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+pub fn foreground_window_insets() -> Option<WindowInsets> {
+    windows::foreground_window_insets()
+}
+
+// TODO: Other platforms need insets set in settings.toml
+#[cfg(not(target_os = "windows"))]
+pub fn foreground_window_insets() -> Option<WindowInsets> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt, path::{Path, PathBuf}};
+
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPARAM, MAX_PATH, TRUE};
+    use winapi::shared::windef::{HWND, POINT, RECT};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+    use winapi::um::winuser::{ClientToScreen, EnumWindows, GetClientRect, GetForegroundWindow, GetWindowRect, GetWindowTextLengthW, GetWindowTextW, GetWindowThreadProcessId, IsIconic, IsWindowVisible};
+
+    use super::{WindowGeometry, WindowInsets};
+
+    struct WindowSearch<'a> {
+        matches: &'a dyn Fn(&str, &Path) -> bool,
+        found: Option<WindowGeometry>,
+    }
+
+    pub fn find_window(matches: &dyn Fn(&str, &Path) -> bool) -> Option<WindowGeometry> {
+        let mut search = WindowSearch { matches: matches, found: None };
+        unsafe {
+            // Returns false when we stop early on a match, which isn't an error
+            EnumWindows(Some(check_window), &mut search as *mut WindowSearch as LPARAM);
+        }
+        search.found
+    }
+
+    pub fn foreground_window_insets() -> Option<WindowInsets> {
+        unsafe {
+            let hwnd = GetForegroundWindow();
+            if hwnd.is_null() {
+                return None
+            }
+            client_insets(hwnd)
+        }
+    }
+
+    unsafe fn client_insets(hwnd: HWND) -> Option<WindowInsets> {
+        let mut frame: RECT = std::mem::zeroed();
+        let mut client: RECT = std::mem::zeroed();
+        let mut client_origin = POINT { x: 0, y: 0 };
+        if GetWindowRect(hwnd, &mut frame) == FALSE || GetClientRect(hwnd, &mut client) == FALSE || ClientToScreen(hwnd, &mut client_origin) == FALSE {
+            return None
+        }
+        // GetClientRect is relative to the client area itself, so right and bottom are its size
+        Some(WindowInsets {
+            left: (client_origin.x - frame.left) as f32,
+            top: (client_origin.y - frame.top) as f32,
+            right: (frame.right - (client_origin.x + client.right)) as f32,
+            bottom: (frame.bottom - (client_origin.y + client.bottom)) as f32,
+        })
+    }
+
+    unsafe extern "system" fn check_window(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let search = &mut *(lparam as *mut WindowSearch);
+        // Minimized windows report a placeholder rectangle far off-screen
+        if IsWindowVisible(hwnd) == FALSE || IsIconic(hwnd) != FALSE {
+            return TRUE
+        }
+        let title_length = GetWindowTextLengthW(hwnd);
+        if title_length == 0 {
+            return TRUE
+        }
+        let mut title_buffer = vec![0u16; title_length as usize + 1];
+        let copied_length = GetWindowTextW(hwnd, title_buffer.as_mut_ptr(), title_buffer.len() as i32);
+        let title = String::from_utf16_lossy(&title_buffer[..copied_length as usize]);
+        if !(search.matches)(&title, &window_process_path(hwnd)) {
+            return TRUE
+        }
+        let mut rect: RECT = std::mem::zeroed();
+        if GetWindowRect(hwnd, &mut rect) == FALSE {
+            return TRUE
+        }
+        search.found = Some(WindowGeometry {
+            x: rect.left as f32,
+            y: rect.top as f32,
+            width: (rect.right - rect.left) as f32,
+            height: (rect.bottom - rect.top) as f32,
+            insets: client_insets(hwnd),
+        });
+        FALSE
+    }
+
+    unsafe fn window_process_path(hwnd: HWND) -> PathBuf {
+        let mut process_id: DWORD = 0;
+        GetWindowThreadProcessId(hwnd, &mut process_id);
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id);
+        if process.is_null() {
+            return PathBuf::new()
+        }
+        let mut path_buffer = vec![0u16; MAX_PATH];
+        let mut path_length = path_buffer.len() as DWORD;
+        let succeeded = QueryFullProcessImageNameW(process, 0, path_buffer.as_mut_ptr(), &mut path_length);
+        CloseHandle(process);
+        if succeeded == FALSE {
+            return PathBuf::new()
+        }
+        PathBuf::from(OsString::from_wide(&path_buffer[..path_length as usize]))
+    }
+}